@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use clap::Clap;
+use futures_util::future::{self, FutureExt};
+use zbus::fdo;
+use zvariant::{OwnedValue, Value};
+
+use unifiedpush_gotify_lib::{Error, MANAGER_INTERFACE, MANAGER_PATH};
+use unifiedpush_gotify_lib::gotify::{GotifyClient, GotifyError};
+use unifiedpush_gotify_lib::config::{self, Bus, Config, Overrides};
+
+/// Inspects and manages the registrations of the running daemon
+#[derive(Clap)]
+#[clap(version = "0.1", author = "vurpo")]
+struct Options {
+    /// Path of the daemon's configuration file, used to find its bus and bus name
+    #[clap(long = "config", env = "UNIFIEDPUSH_GOTIFY_CONFIG")]
+    config: Option<PathBuf>,
+    /// "session" or "system"
+    #[clap(long = "bus")]
+    bus: Option<Bus>,
+    #[clap(long = "bus-name")]
+    bus_name: Option<String>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Lists the registered apps and their endpoints
+    List,
+    /// Shows the registrations of an app, with its last seen message and queued messages
+    Show { appid: String },
+    /// Unregisters the app with the token, deleting its Gotify application
+    Unregister { token: String },
+    /// Sends the app with the token its endpoint again
+    ResendEndpoint { token: String },
+    /// Shows the last seen message and the delivery queue of each account
+    Status,
+    /// Shows the state of each account's connection to Gotify
+    Connections,
+    /// Makes every account check for missed messages, reconnecting right away if disconnected
+    CatchUp,
+    /// Makes the daemon read its configuration again
+    Reload,
+    /// Sends the app a test push, and reports when the daemon received it and the app acknowledged it
+    TestPush {
+        appid: String,
+        /// Send the message to Gotify's /message instead of the app's push endpoint
+        #[clap(long = "direct")]
+        direct: bool,
+        /// Seconds to wait for the daemon and the app
+        #[clap(long = "timeout", default_value = "30")]
+        timeout: f64,
+    },
+}
+
+/// Why a command failed, as opposed to the daemon's `Error`s it passes on
+#[derive(Debug)]
+enum CtlError {
+    /// Reading the configuration, calling the daemon or Gotify failed
+    Daemon(Error),
+    NotRegistered(String),
+    InvalidOption(String),
+    /// The daemon's reply lacks a field the command needs
+    IncompleteReply(&'static str),
+    /// The test push wasn't received or acknowledged in time
+    TestPushFailed,
+}
+
+impl fmt::Display for CtlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CtlError::Daemon(e) => write!(f, "{}", e),
+            CtlError::NotRegistered(appid) => write!(f, "{} isn't registered", appid),
+            CtlError::InvalidOption(e) => write!(f, "{}", e),
+            CtlError::IncompleteReply(field) => write!(f, "The daemon's reply is missing {}", field),
+            CtlError::TestPushFailed => write!(f, "Test push failed"),
+        }
+    }
+}
+
+impl<T: Into<Error>> From<T> for CtlError {
+    fn from(error: T) -> Self {
+        CtlError::Daemon(error.into())
+    }
+}
+
+type Dict = HashMap<String, OwnedValue>;
+
+fn field(dict: &Dict, key: &str) -> String {
+    match dict.get(key).map(|v| &**v) {
+        Some(Value::Str(s)) => s.as_str().to_owned(),
+        Some(Value::I32(n)) => n.to_string(),
+        Some(Value::I64(n)) => n.to_string(),
+        Some(value) => format!("{:?}", value),
+        None => String::new(),
+    }
+}
+
+fn load_config(opts: &Options) -> Result<Config, CtlError> {
+    let path = match &opts.config {
+        Some(path) => path.clone(),
+        None => config::default_config_dir()?.join("config.toml"),
+    };
+    let mut config = Config::load(&path)?;
+    config.apply(Overrides::from_env()?);
+    config.apply(Overrides {
+        bus: opts.bus,
+        bus_name: opts.bus_name.clone(),
+        ..Overrides::default()
+    });
+    Ok(config)
+}
+
+fn call<B>(connection: &zbus::Connection, bus_name: &str, method: &str, body: &B) -> Result<zbus::Message, CtlError>
+where B: serde::Serialize + zvariant::Type {
+    Ok(connection.call_method(Some(bus_name), MANAGER_PATH, Some(MANAGER_INTERFACE), method, body)?)
+}
+
+fn int_field(dict: &Dict, key: &str) -> Option<i32> {
+    match dict.get(key).map(|v| &**v) {
+        Some(Value::I32(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+/// The signals of the management interface a test push is followed with
+enum Observed {
+    MessageReceived { account: String, gotify_id: i32, message_id: i32 },
+    PushDelivered { appid: String, account: String, message_id: i32 },
+}
+
+fn observed(message: &zbus::Message) -> Option<Observed> {
+    let header = message.header().ok()?;
+    if header.message_type().ok()? != zbus::MessageType::Signal || header.interface().ok()?? != MANAGER_INTERFACE {
+        return None;
+    }
+    match header.member().ok()?? {
+        "MessageReceived" => message.body::<(String, i32, i32)>().ok()
+            .map(|(account, gotify_id, message_id)| Observed::MessageReceived { account, gotify_id, message_id }),
+        "PushDelivered" => message.body::<(String, String, i32)>().ok()
+            .map(|(appid, account, message_id)| Observed::PushDelivered { appid, account, message_id }),
+        _ => None,
+    }
+}
+
+/// Posts a message for the app and follows it through the daemon by its signals
+async fn test_push(connection: &zbus::azync::Connection, bus_name: &str, appid: &str, direct: bool, timeout: Duration) -> Result<(), CtlError> {
+    let registrations: Vec<Dict> = connection.call_method(Some(bus_name), MANAGER_PATH, Some(MANAGER_INTERFACE), "GetApp", &appid).await?.body()?;
+    let registration = registrations.first().ok_or_else(|| CtlError::NotRegistered(appid.to_owned()))?;
+    let account = field(registration, "account");
+    let gotify_id = int_field(registration, "gotify_id").ok_or(CtlError::IncompleteReply("gotify_id"))?;
+
+    // Subscribe before sending, so the signals can't be missed
+    let rule = format!("type='signal',sender='{}',path='{}',interface='{}'", bus_name, MANAGER_PATH, MANAGER_INTERFACE);
+    fdo::AsyncDBusProxy::new(connection)?.add_match(&rule).await?;
+
+    let data = format!("Test push from gotify_ctl at {}", SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+    let started = Instant::now();
+    let mut message_id = if direct {
+        let gotify = GotifyClient::new(&field(registration, "gotify_url"), &field(registration, "gotify_token"));
+        let message = gotify.create_message("Test push", &data).await?;
+        println!("Sent message {} to Gotify's /message in {} ms", message.id, started.elapsed().as_millis());
+        Some(message.id)
+    } else {
+        let endpoint = field(registration, "endpoint");
+        reqwest::Client::new().post(endpoint.as_str()).body(data).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(GotifyError::from)?;
+        println!("Sent message to {} in {} ms", endpoint, started.elapsed().as_millis());
+        None
+    };
+
+    let mut received = false;
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let signal = connection.receive_specific(|message| future::ready(Ok(observed(message).is_some())).boxed());
+        let message = match tokio::time::timeout_at(deadline, signal).await {
+            Ok(Ok(message)) => message,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => break,
+        };
+        match observed(&message) {
+            Some(Observed::MessageReceived { account: a, gotify_id: g, message_id: id })
+                if !received && a == account && g == gotify_id && message_id.map_or(true, |m| m == id) => {
+                println!("Daemon received message {} from Gotify after {} ms", id, started.elapsed().as_millis());
+                message_id = Some(id);
+                received = true;
+            }
+            Some(Observed::PushDelivered { appid: a, account: acc, message_id: id })
+                if a == appid && acc == account && message_id == Some(id) => {
+                println!("{} acknowledged the push after {} ms", appid, started.elapsed().as_millis());
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    if received {
+        println!("{} didn't acknowledge the push within {} seconds", appid, timeout.as_secs_f64());
+    } else {
+        println!("The daemon didn't receive the message within {} seconds", timeout.as_secs_f64());
+    }
+    Err(CtlError::TestPushFailed)
+}
+
+fn print_registration(registration: &Dict) {
+    println!("{}", field(registration, "appid"));
+    for key in &["token", "account", "gotify_id", "description", "dbus_version", "registered_at", "endpoint", "last_seen_message", "pending"] {
+        if registration.contains_key(*key) {
+            println!("    {}: {}", key, field(registration, key));
+        }
+    }
+}
+
+fn run(opts: Options) -> Result<(), CtlError> {
+    let config = load_config(&opts)?;
+    let connection = match config.bus {
+        Bus::Session => zbus::Connection::new_session()?,
+        Bus::System => zbus::Connection::new_system()?,
+    };
+    let bus_name = config.bus_name.as_str();
+    match opts.command {
+        Command::List => {
+            let registrations: Vec<Dict> = call(&connection, bus_name, "ListRegistrations", &())?.body()?;
+            for registration in &registrations {
+                println!("{}\t{}\t{}\t{}", field(registration, "appid"), field(registration, "account"), field(registration, "token"), field(registration, "endpoint"));
+            }
+        }
+        Command::Show { appid } => {
+            let registrations: Vec<Dict> = call(&connection, bus_name, "GetApp", &appid)?.body()?;
+            if registrations.is_empty() {
+                return Err(CtlError::NotRegistered(appid));
+            }
+            registrations.iter().for_each(print_registration);
+        }
+        Command::Unregister { token } => {
+            call(&connection, bus_name, "Unregister", &token)?;
+            println!("Unregistered");
+        }
+        Command::ResendEndpoint { token } => {
+            call(&connection, bus_name, "ResendEndpoint", &token)?;
+            println!("Endpoint sent");
+        }
+        Command::Status => {
+            let accounts: Vec<Dict> = call(&connection, bus_name, "GetQueueStatus", &())?.body()?;
+            for account in &accounts {
+                println!("{}", field(account, "account"));
+                println!("    last seen message: {}", field(account, "last_seen_message"));
+                println!("    pending: {}", field(account, "pending"));
+                println!("    delivered, not deleted from Gotify: {}", field(account, "undeleted"));
+            }
+        }
+        Command::Connections => {
+            let states: HashMap<String, String> = call(&connection, bus_name, "GetConnectionStates", &())?.body()?;
+            let mut states: Vec<_> = states.into_iter().collect();
+            states.sort();
+            for (account, state) in states {
+                println!("{}\t{}", account, state);
+            }
+        }
+        Command::CatchUp => {
+            call(&connection, bus_name, "CatchUp", &())?;
+        }
+        Command::Reload => {
+            let restart_required: Vec<String> = call(&connection, bus_name, "ReloadConfig", &())?.body()?;
+            if restart_required.is_empty() {
+                println!("Configuration reloaded");
+            } else {
+                println!("Configuration reloaded, changes to {} take effect after a restart", restart_required.join(", "));
+            }
+        }
+        Command::TestPush { appid, direct, timeout } => {
+            if !(timeout > 0.0 && timeout.is_finite()) {
+                return Err(CtlError::InvalidOption(format!("--timeout must be a positive number of seconds, not {}", timeout)));
+            }
+            let runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(test_push(connection.inner(), bus_name, &appid, direct, Duration::from_secs_f64(timeout)))?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let opts: Options = Options::parse();
+    if let Err(e) = run(opts) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
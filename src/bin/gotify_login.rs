@@ -1,21 +1,44 @@
 use std::error::Error;
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use directories_next::ProjectDirs;
 use clap::Clap;
-use serde_json::json;
-use serde::Deserialize;
+use r2d2_sqlite::SqliteConnectionManager;
+use zbus::fdo;
 
-use unifiedpush_gotify_lib::LoginFile;
+use unifiedpush_gotify_lib::{Account, LoginFile, LoginsFile, DEFAULT_ACCOUNT, files, migrations};
+use unifiedpush_gotify_lib::{delete_account_data, get_account_connections};
+use unifiedpush_gotify_lib::config::{self, Bus, Config, Overrides};
+use unifiedpush_gotify_lib::gotify::{GotifyClient, GotifyError};
+use unifiedpush_gotify_lib::secrets::SecretService;
 
 #[derive(Debug)]
 enum LoginError {
     NoConfigDirectory,
+    MissingOption(&'static str),
+    NotLoggedIn(String),
+    DaemonRunning,
     InvalidServerOrCredentials,
     InvalidServerResponse,
     CantWriteLoginFile,
     StdError(Box<dyn Error>)
 }
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::NoConfigDirectory => write!(f, "Can't find the configuration directory"),
+            LoginError::MissingOption(option) => write!(f, "Missing {}", option),
+            LoginError::NotLoggedIn(account) => write!(f, "Account {} isn't logged in", account),
+            LoginError::DaemonRunning => write!(f, "The daemon is running, stop it before logging out"),
+            LoginError::InvalidServerOrCredentials => write!(f, "Invalid server or credentials"),
+            LoginError::InvalidServerResponse => write!(f, "Invalid response from the server"),
+            LoginError::CantWriteLoginFile => write!(f, "Can't write the login file"),
+            LoginError::StdError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
 impl<T: 'static> From<T> for LoginError where T: Error {
     fn from(error: T) -> Self {
         LoginError::StdError(Box::new(error))
@@ -26,59 +49,257 @@ impl<T: 'static> From<T> for LoginError where T: Error {
 #[clap(version = "0.1", author = "vurpo")]
 struct Options {
     #[clap(long = "url")]
-    url: String,
+    url: Option<String>,
     #[clap(long = "username")]
-    username: String
+    username: Option<String>,
+    /// Log in with an existing client token instead of a username and password, "-" reads it from stdin
+    #[clap(long = "token", env = "GOTIFY_CLIENT_TOKEN", hide_env_values = true, conflicts_with = "username")]
+    token: Option<String>,
+    /// Name of the account to log in or out. Logging in replaces an existing account of the same name.
+    #[clap(long = "account", global = true)]
+    account: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Deserialize)]
-struct GotifyResponse {
-    token: String
+#[derive(Clap)]
+enum Command {
+    /// Deletes this device's client from the Gotify server, then the account's credentials and registrations
+    Logout {
+        /// Also delete the Gotify applications of the apps registered through this device
+        #[clap(long = "delete-applications")]
+        delete_applications: bool,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<(), LoginError> {
-    let opts: Options = Options::parse();
+/// Adds the login to the accounts already in `login.json`. A lone default account keeps the old single-account format.
+fn merge_login(existing: Option<LoginsFile>, name: &str, login: LoginFile) -> LoginsFile {
+    match existing {
+        None | Some(LoginsFile::Single(_)) if name == DEFAULT_ACCOUNT => LoginsFile::Single(login),
+        existing => {
+            let mut accounts = existing.map(LoginsFile::into_accounts).unwrap_or_default();
+            let account = Account { name: name.to_owned(), login };
+            match accounts.iter_mut().find(|a| a.name == account.name) {
+                Some(a) => *a = account,
+                None => accounts.push(account),
+            }
+            LoginsFile::Accounts { accounts }
+        }
+    }
+}
+
+/// Writes `login.json` readable only by the user, as it can hold device tokens
+fn write_login_file(path: &Path, logins: &LoginsFile) -> Result<(), Box<dyn Error>> {
+    files::write_private(path, &serde_json::to_vec(logins)?)?;
+    Ok(())
+}
+
+/// Removes the account from `login.json`, returning `None` if no accounts are left
+fn remove_login(existing: LoginsFile, name: &str) -> Option<LoginsFile> {
+    let mut accounts: Vec<Account> = existing.into_accounts().into_iter().filter(|a| a.name != name).collect();
+    if accounts.is_empty() {
+        None
+    } else if accounts.len() == 1 && accounts[0].name == DEFAULT_ACCOUNT {
+        Some(LoginsFile::Single(accounts.remove(0).login))
+    } else {
+        Some(LoginsFile::Accounts { accounts })
+    }
+}
+
+/// Whether the daemon owns its bus name, in which case it would keep using the account
+fn daemon_running(config: &Config) -> Result<bool, unifiedpush_gotify_lib::Error> {
+    let connection = match config.bus {
+        Bus::Session => zbus::Connection::new_session()?,
+        Bus::System => zbus::Connection::new_system()?,
+    };
+    let running = fdo::DBusProxy::new(&connection)?.name_has_owner(&config.bus_name)?;
+    Ok(running)
+}
+
+/// Keeps the device token in the keyring, or in `login.json` if there's no keyring, and adds the account to `login.json`.
+/// A daemon on the system bus can't read the user's keyring, so the token always goes to `login.json` then.
+fn save_login(config: &Config, login_file_path: &Path, existing: Option<LoginsFile>, account: &str, base_url: &str, device_token: String, created_client: bool) -> Result<(), LoginError> {
+    let stored = match config.bus {
+        Bus::Session => SecretService::connect().and_then(|s| s.store_token(account, base_url, &device_token)),
+        Bus::System => Err(unifiedpush_gotify_lib::Error::SecretService("the daemon runs on the system bus".to_owned())),
+    };
+    let token = match stored {
+        Ok(()) => String::new(),
+        Err(e) => {
+            eprintln!("Can't store the device token in the Secret Service ({}), saving it in {} instead", e, login_file_path.display());
+            device_token
+        }
+    };
+    let login = LoginFile { gotify_base_url: base_url.to_owned(), gotify_device_token: token, created_client };
+    let logins = merge_login(existing, account, login);
+    match write_login_file(login_file_path, &logins) {
+        Ok(()) => {
+            println!("Successfully signed in to Gotify as account {}!", account);
+            Ok(())
+        }
+        Err(_) => Err(LoginError::CantWriteLoginFile)
+    }
+}
+
+fn login_error(error: GotifyError) -> LoginError {
+    match error {
+        GotifyError::Status(_, _) => LoginError::InvalidServerOrCredentials,
+        GotifyError::Http(e) if e.is_decode() => LoginError::InvalidServerResponse,
+        e => LoginError::StdError(Box::new(e))
+    }
+}
+
+/// Creates a client for this device with the user's password
+async fn login(opts: &Options, config: &Config, login_file_path: &Path, existing: Option<LoginsFile>, account: &str) -> Result<(), LoginError> {
+    let url = opts.url.as_deref().ok_or(LoginError::MissingOption("--url"))?;
+    let username = opts.username.as_deref().ok_or(LoginError::MissingOption("--username or --token"))?;
 
-    let project_dirs = ProjectDirs::from("fi", "vurpo", "UnifiedPushGotify")
-        .ok_or(LoginError::NoConfigDirectory)?;
-    let config_dir = project_dirs.config_dir();
-    std::fs::create_dir_all(config_dir)?;
-    
     let password = rpassword::read_password_from_tty(Some("Password: "))?;
 
-    let client = reqwest::Client::new();
-    if let Ok(new_device) = client.post(&format!("{}/client", opts.url.trim_end_matches("/")))
-        .header("Content-Type", "application/json")
-        .basic_auth(opts.username, Some(password))
-        .json(&json!{{"name": "UnifiedPush-dbus-Gotify"}})
-        .send().await
-        .map_err(|_| ())
-        .and_then(|r| if r.status() == 200 { Ok(r) } else { Err(()) }) {
-            if let Ok(response) = new_device.json::<GotifyResponse>().await {
-                let config = crate::LoginFile {
-                    gotify_base_url: opts.url.trim_end_matches("/").to_owned(),
-                    gotify_device_token: response.token
-                };
-                let login_file_path = {
-                    let mut buf = PathBuf::from(config_dir);
-                    buf.push("login.json");
-                    buf
-                };
-                if let Ok(file) = File::create(login_file_path.as_path()) {
-                    if let Ok(_) = serde_json::to_writer(&file, &config) {
-                        println!("Successfully signed in to Gotify!");
-                        Ok(())
-                    } else {
-                        Err(LoginError::CantWriteLoginFile)
-                    }
-                } else {
-                    Err(LoginError::CantWriteLoginFile)
-                }
-            } else {
-                Err(LoginError::InvalidServerResponse)
+    let client = GotifyClient::with_basic_auth(url, username, &password);
+    let new_device = client.create_client("UnifiedPush-dbus-Gotify").await.map_err(login_error)?;
+    save_login(config, login_file_path, existing, account, client.base_url(), new_device.token, true)
+}
+
+/// Uses a client token the user already has, checking it with the server first
+async fn login_with_token(opts: &Options, config: &Config, token: &str, login_file_path: &Path, existing: Option<LoginsFile>, account: &str) -> Result<(), LoginError> {
+    let url = opts.url.as_deref().ok_or(LoginError::MissingOption("--url"))?;
+    let token = if token == "-" {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        line.trim().to_owned()
+    } else {
+        token.to_owned()
+    };
+    if token.is_empty() {
+        return Err(LoginError::MissingOption("--token"));
+    }
+
+    // Application tokens can't read the current user, so this also makes sure it's a client token
+    let client = GotifyClient::new(url, &token);
+    let user = client.current_user().await.map_err(login_error)?;
+    println!("The token belongs to Gotify user {}", user.name);
+    save_login(config, login_file_path, existing, account, client.base_url(), token, false)
+}
+
+/// Deletes the device's client, and optionally the Gotify applications of its registrations, from the server.
+/// Clients the user logged in with by token weren't created here, so they're left alone.
+async fn revoke(client: &GotifyClient, login: &LoginFile, token: &str, pool: Option<&r2d2::Pool<SqliteConnectionManager>>, account: &str, delete_applications: bool) -> Result<(), LoginError> {
+    if let (true, Some(pool)) = (delete_applications, pool) {
+        for connection in get_account_connections(pool, account)? {
+            match client.delete_application(connection.gotify_id).await {
+                Ok(()) => println!("Deleted the Gotify application of {}", connection.appid),
+                Err(e) if e.is_not_found() => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    if !login.created_client {
+        println!("Keeping the client on {}, as gotify_login didn't create it. Delete it in Gotify if it's no longer needed.", client.base_url());
+        return Ok(());
+    }
+    match client.list_clients().await {
+        Ok(clients) => match clients.iter().find(|c| c.token == token) {
+            Some(device) => {
+                client.delete_client(device.id).await?;
+                println!("Deleted the client {} from {}", device.name, client.base_url());
             }
+            None => println!("The device's client was already deleted from {}", client.base_url()),
+        },
+        Err(e) if e.is_unauthorized() => println!("The device's client was already deleted from {}", client.base_url()),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+async fn logout(config: &Config, config_dir: &Path, login_file_path: &Path, existing: Option<LoginsFile>, account: &str, delete_applications: bool) -> Result<(), LoginError> {
+    let existing = existing.ok_or_else(|| LoginError::NotLoggedIn(account.to_owned()))?;
+    let login = existing.clone().into_accounts().into_iter()
+        .find(|a| a.name == account)
+        .ok_or_else(|| LoginError::NotLoggedIn(account.to_owned()))?
+        .login;
+
+    if daemon_running(config).unwrap_or(false) {
+        return Err(LoginError::DaemonRunning);
+    }
+
+    // Tokens missing from login.json are in the keyring
+    let secrets = if login.gotify_device_token.is_empty() { Some(SecretService::connect()?) } else { None };
+    let token = match &secrets {
+        Some(secrets) => secrets.load_token(account, &login.gotify_base_url)?.unwrap_or_default(),
+        None => login.gotify_device_token.clone(),
+    };
+
+    let db_path = config.database_path(config_dir);
+    let pool = if db_path.exists() {
+        let pool = r2d2::Pool::new(SqliteConnectionManager::file(&db_path))?;
+        migrations::migrate(&mut *pool.get()?)?;
+        Some(pool)
     } else {
-        Err(LoginError::InvalidServerOrCredentials)
+        None
+    };
+
+    if token.is_empty() {
+        println!("There is no device token for account {}, only removing it from this device", account);
+    } else {
+        let client = GotifyClient::new(&login.gotify_base_url, &token);
+        revoke(&client, &login, &token, pool.as_ref(), account, delete_applications).await?;
+    }
+
+    if let Some(secrets) = &secrets {
+        secrets.delete_token(account, &login.gotify_base_url)?;
     }
-}
\ No newline at end of file
+    match remove_login(existing, account) {
+        Some(logins) => {
+            write_login_file(login_file_path, &logins).map_err(|_| LoginError::CantWriteLoginFile)?;
+            if let Some(pool) = &pool {
+                delete_account_data(pool, account)?;
+            }
+        }
+        None => {
+            std::fs::remove_file(login_file_path)?;
+            drop(pool);
+            if db_path.exists() {
+                std::fs::remove_file(&db_path)?;
+            }
+        }
+    }
+    println!("Logged out of account {}", account);
+    Ok(())
+}
+
+async fn run(opts: Options) -> Result<(), LoginError> {
+    let project_dirs = ProjectDirs::from("fi", "vurpo", "UnifiedPushGotify")
+        .ok_or(LoginError::NoConfigDirectory)?;
+    let config_dir = project_dirs.config_dir();
+    files::create_private_dir(config_dir)?;
+    let login_file_path = {
+        let mut buf = PathBuf::from(config_dir);
+        buf.push("login.json");
+        buf
+    };
+    let existing: Option<LoginsFile> = match File::open(&login_file_path) {
+        Ok(file) => Some(serde_json::from_reader(&file)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let account = opts.account.as_deref().unwrap_or(DEFAULT_ACCOUNT);
+    let config = config::Source { path: config_dir.join("config.toml"), overrides: Overrides::default() }.load()?;
+
+    match &opts.command {
+        Some(Command::Logout { delete_applications }) => logout(&config, config_dir, &login_file_path, existing, account, *delete_applications).await,
+        None => match &opts.token {
+            Some(token) => login_with_token(&opts, &config, token, &login_file_path, existing, account).await,
+            None => login(&opts, &config, &login_file_path, existing, account).await,
+        },
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let opts: Options = Options::parse();
+    if let Err(e) = run(opts).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Mutex;
+
+use std::future::Future;
+
+use futures_util::future::{self, FutureExt};
+use futures_util::sink::SinkExt;
+use lazy_static::lazy_static;
+use tokio::sync::{broadcast, oneshot};
+use zbus::MessageType;
+use log::warn;
+
+const DBUS_SERVICE: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+const DBUS_INTERFACE: &str = "org.freedesktop.DBus";
+
+/// `RequestName` flag to take the name over from its current owner
+pub(crate) const DBUS_NAME_FLAG_REPLACE_EXISTING: u32 = 2;
+
+lazy_static! {
+    /// Tasks waiting for the reply to a call they sent, by the serial number of the call
+    static ref PENDING_REPLIES: Mutex<HashMap<u32, oneshot::Sender<zbus::Message>>> = Mutex::new(HashMap::new());
+    /// `NameOwnerChanged` signals of the bus, as `(name, new owner)`
+    static ref OWNER_CHANGES: broadcast::Sender<(String, String)> = broadcast::channel(64).0;
+    /// Method calls read by `call_reading`, for `next_method_call` to return first
+    static ref DEFERRED_CALLS: Mutex<VecDeque<zbus::Message>> = Mutex::new(VecDeque::new());
+}
+
+/// Forgets the waiting task when the call is dropped, e.g. by a timeout
+struct PendingReply(u32);
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        PENDING_REPLIES.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Calls a method on the daemon's connection and waits for the reply, which `next_method_call` hands over.
+/// A zbus connection only has room for one task waiting on it, so everything calls through here
+/// instead of `call_method` or proxies, which would take replies meant for others off the connection.
+pub(crate) async fn call<B>(
+    conn: &zbus::azync::Connection,
+    destination: &str,
+    path: &str,
+    interface: &str,
+    method: &str,
+    body: &B) -> zbus::Result<zbus::Message>
+where
+    B: serde::ser::Serialize + zvariant::Type,
+{
+    let mut message = zbus::Message::method(conn.unique_name(), Some(destination), path, Some(interface), method, body)?;
+    let serial = conn.assign_serial_num(&mut message).await?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    PENDING_REPLIES.lock().unwrap().insert(serial, reply_tx);
+    let _pending = PendingReply(serial);
+    conn.sink().await.send(message).await?;
+    let reply = reply_rx.await
+        .map_err(|_| zbus::Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "socket closed")))?;
+    if reply.header()?.message_type()? == MessageType::Error {
+        return Err(reply.into());
+    }
+    Ok(reply)
+}
+
+/// Waits for `call` while nothing else reads the connection, reading it for the reply.
+/// Method calls read meanwhile are returned by the following `next_method_call`s.
+pub(crate) async fn call_reading<F, T>(conn: &zbus::azync::Connection, call: F) -> zbus::Result<T>
+where
+    F: Future<Output = zbus::Result<T>>,
+{
+    tokio::pin!(call);
+    loop {
+        tokio::select! {
+            result = &mut call => return result,
+            message = next_method_call(conn) => match message {
+                Ok(message) => DEFERRED_CALLS.lock().unwrap().push_back(message),
+                Err(e @ zbus::Error::Io(_)) => return Err(e),
+                Err(e) => warn!("D-Bus error: {}", e),
+            },
+        }
+    }
+}
+
+/// Returns the `RequestName` reply code
+pub(crate) async fn request_name(conn: &zbus::azync::Connection, name: &str, flags: u32) -> zbus::Result<u32> {
+    Ok(call(conn, DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "RequestName", &(name, flags)).await?.body()?)
+}
+
+/// Returns the `ReleaseName` reply code
+pub(crate) async fn release_name(conn: &zbus::azync::Connection, name: &str) -> zbus::Result<u32> {
+    Ok(call(conn, DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "ReleaseName", &name).await?.body()?)
+}
+
+pub(crate) async fn name_has_owner(conn: &zbus::azync::Connection, name: &str) -> zbus::Result<bool> {
+    Ok(call(conn, DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "NameHasOwner", &name).await?.body()?)
+}
+
+pub(crate) async fn connection_unix_user(conn: &zbus::azync::Connection, name: &str) -> zbus::Result<u32> {
+    Ok(call(conn, DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "GetConnectionUnixUser", &name).await?.body()?)
+}
+
+pub(crate) async fn start_service_by_name(conn: &zbus::azync::Connection, name: &str) -> zbus::Result<u32> {
+    Ok(call(conn, DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "StartServiceByName", &(name, 0u32)).await?.body()?)
+}
+
+/// Has the bus send the owner changes of `name`, until `unwatch_name` is called with the same name
+pub(crate) async fn watch_name(conn: &zbus::azync::Connection, name: &str) -> zbus::Result<()> {
+    call(conn, DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "AddMatch", &owner_change_rule(name)).await?;
+    Ok(())
+}
+
+pub(crate) async fn unwatch_name(conn: &zbus::azync::Connection, name: &str) -> zbus::Result<()> {
+    call(conn, DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "RemoveMatch", &owner_change_rule(name)).await?;
+    Ok(())
+}
+
+fn owner_change_rule(name: &str) -> String {
+    format!("type='signal',sender='{}',interface='{}',member='NameOwnerChanged',arg0='{}'", DBUS_SERVICE, DBUS_INTERFACE, name)
+}
+
+/// Owner changes of the names that are watched, as `(name, new owner)`.
+/// Subscribe before watching a name, so no change is missed.
+pub(crate) fn owner_changes() -> broadcast::Receiver<(String, String)> {
+    OWNER_CHANGES.subscribe()
+}
+
+/// Reads the connection up to the next method call. Replies and owner changes read on the way
+/// are handed to the tasks waiting for them, other messages are dropped.
+pub(crate) async fn next_method_call(conn: &zbus::azync::Connection) -> zbus::Result<zbus::Message> {
+    if let Some(message) = DEFERRED_CALLS.lock().unwrap().pop_front() {
+        return Ok(message);
+    }
+    loop {
+        let message = match conn.receive_specific(|_| future::ready(Ok(true)).boxed()).await {
+            Ok(message) => message,
+            Err(e) => {
+                if let zbus::Error::Io(_) = e {
+                    // Nothing will answer the waiting calls anymore
+                    PENDING_REPLIES.lock().unwrap().clear();
+                }
+                return Err(e);
+            }
+        };
+        if let Some(message) = dispatch(message) {
+            return Ok(message);
+        }
+    }
+}
+
+fn dispatch(message: zbus::Message) -> Option<zbus::Message> {
+    let (message_type, reply_serial) = match message.header() {
+        Ok(header) => (header.message_type().ok(), header.reply_serial().ok().flatten()),
+        Err(e) => {
+            warn!("Dropping unreadable D-Bus message: {}", e);
+            return None;
+        }
+    };
+    match message_type {
+        Some(MessageType::MethodCall) => return Some(message),
+        Some(MessageType::MethodReturn) | Some(MessageType::Error) => {
+            let waiting = reply_serial.and_then(|serial| PENDING_REPLIES.lock().unwrap().remove(&serial));
+            if let Some(waiting) = waiting {
+                let _ = waiting.send(message);
+            }
+        }
+        Some(MessageType::Signal) if is_owner_change(&message) => {
+            if let Ok((name, _, new_owner)) = message.body::<(String, String, String)>() {
+                let _ = OWNER_CHANGES.send((name, new_owner));
+            }
+        }
+        _ => {}
+    }
+    None
+}
+
+fn is_owner_change(message: &zbus::Message) -> bool {
+    message.header().map_or(false, |h| {
+        h.sender().ok().flatten() == Some(DBUS_SERVICE)
+            && h.member().ok().flatten() == Some("NameOwnerChanged")
+    })
+}
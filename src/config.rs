@@ -0,0 +1,499 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use directories_next::ProjectDirs;
+use serde::Deserialize;
+
+use crate::{Account, Error, is_valid_bus_name};
+
+pub const DEFAULT_BUS_NAME: &str = "org.unifiedpush.Distributor.gotify";
+
+/// Prefix of the environment variables that override settings from `config.toml`
+pub const ENV_PREFIX: &str = "UNIFIEDPUSH_GOTIFY_";
+
+/// The directory holding `login.json`, `config.toml` and by default the database
+pub fn default_config_dir() -> Result<PathBuf, Error> {
+    ProjectDirs::from("fi", "vurpo", "UnifiedPushGotify")
+        .map(|dirs| dirs.config_dir().to_owned())
+        .ok_or_else(|| Error::Config("Can't determine the configuration directory".to_owned()))
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    Session,
+    System,
+}
+
+impl FromStr for Bus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "session" => Ok(Bus::Session),
+            "system" => Ok(Bus::System),
+            _ => Err(Error::Config(format!("bus must be \"session\" or \"system\", not \"{}\"", s))),
+        }
+    }
+}
+
+/// Intervals and timeouts, in seconds
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// How often the server is checked for applications deleted by the user
+    pub removed_apps_interval: f64,
+    /// How often undelivered messages are retried
+    pub retry_interval: f64,
+    /// How long a connector app gets to acknowledge a message
+    pub delivery: f64,
+    /// How long a sleeping connector app gets to start after being activated
+    pub activation: f64,
+    /// How long an app can stay neither running nor activatable before it's considered uninstalled and unregistered
+    pub dead_after: f64,
+    pub ping_interval: f64,
+    /// The connection is dead if nothing arrives for this long after a ping is due
+    pub pong_timeout: f64,
+    /// How long Gotify gets to answer our close frame on shutdown
+    pub close: f64,
+    /// How long running deliveries and database writes get to finish on shutdown
+    pub shutdown: f64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            removed_apps_interval: 120.0,
+            retry_interval: 30.0,
+            delivery: 25.0,
+            activation: 30.0,
+            dead_after: 86400.0,
+            ping_interval: 30.0,
+            pong_timeout: 10.0,
+            close: 5.0,
+            shutdown: 30.0,
+        }
+    }
+}
+
+/// Delays between websocket reconnection attempts
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backoff {
+    /// Seconds before the first attempt
+    pub initial: f64,
+    /// Longest delay in seconds
+    pub max: f64,
+    pub multiplier: f64,
+    /// Fraction by which each delay is randomly varied
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: 1.0,
+            max: 300.0,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+/// Sends new registrations of matching apps to another account than the default one
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// An appid, or an appid prefix ending in `*`
+    pub appid: String,
+    pub account: String,
+}
+
+impl Route {
+    pub fn matches(&self, appid: &str) -> bool {
+        match self.appid.strip_suffix('*') {
+            Some(prefix) => appid.starts_with(prefix),
+            None => appid == self.appid,
+        }
+    }
+}
+
+/// Daemon settings from `config.toml`. Every setting is optional.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The bus the distributor and the connector apps are on
+    pub bus: Bus,
+    pub bus_name: String,
+    /// Defaults to `database.db` in the configuration directory
+    pub database: Option<PathBuf>,
+    /// One of off, error, warn, info, debug or trace. `RUST_LOG` can refine it per module.
+    pub log_level: String,
+    /// Delete messages from the Gotify server once the connector app acknowledged them
+    pub delete_after_delivery: bool,
+    /// Account of apps no route matches, the first one in `login.json` by default
+    pub default_account: Option<String>,
+    /// Checked in order, the first matching route decides
+    pub routes: Vec<Route>,
+    pub timeouts: Timeouts,
+    pub backoff: Backoff,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bus: Bus::Session,
+            bus_name: DEFAULT_BUS_NAME.to_owned(),
+            database: None,
+            log_level: "info".to_owned(),
+            delete_after_delivery: true,
+            default_account: None,
+            routes: Vec::new(),
+            timeouts: Timeouts::default(),
+            backoff: Backoff::default(),
+        }
+    }
+}
+
+/// Where the configuration is read from, kept so the running daemon can read it again
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub path: PathBuf,
+    /// Command line options, the environment is read again on every load
+    pub overrides: Overrides,
+}
+
+impl Source {
+    pub fn load(&self) -> Result<Config, Error> {
+        let mut config = Config::load(&self.path)?;
+        config.apply(Overrides::from_env()?);
+        config.apply(self.overrides.clone());
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Settings given on the command line or in the environment, which take precedence over the file
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub bus: Option<Bus>,
+    pub bus_name: Option<String>,
+    pub database: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub delete_after_delivery: Option<bool>,
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(format!("{}{}", ENV_PREFIX, name)).ok().filter(|v| !v.is_empty())
+}
+
+impl Overrides {
+    /// Reads `UNIFIEDPUSH_GOTIFY_BUS`, `_BUS_NAME`, `_DATABASE`, `_LOG_LEVEL` and `_DELETE_AFTER_DELIVERY`
+    pub fn from_env() -> Result<Self, Error> {
+        Ok(Overrides {
+            bus: env("BUS").map(|v| v.parse()).transpose()?,
+            bus_name: env("BUS_NAME"),
+            database: env("DATABASE").map(PathBuf::from),
+            log_level: env("LOG_LEVEL"),
+            delete_after_delivery: env("DELETE_AFTER_DELIVERY").map(|v| match v.as_str() {
+                "true" | "1" => Ok(true),
+                "false" | "0" => Ok(false),
+                _ => Err(Error::Config(format!("{}DELETE_AFTER_DELIVERY must be true or false, not \"{}\"", ENV_PREFIX, v))),
+            }).transpose()?,
+        })
+    }
+}
+
+fn check_positive(name: &str, value: f64) -> Result<(), Error> {
+    if value.is_finite() && value > 0.0 {
+        Ok(())
+    } else {
+        Err(Error::Config(format!("{} must be a positive number of seconds, not {}", name, value)))
+    }
+}
+
+impl Config {
+    /// Reads the file, or returns the defaults if it doesn't exist
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(Error::Config(format!("Can't read {}: {}", path.display(), e))),
+        };
+        toml::from_str(&text).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn apply(&mut self, overrides: Overrides) {
+        if let Some(bus) = overrides.bus {
+            self.bus = bus;
+        }
+        if let Some(bus_name) = overrides.bus_name {
+            self.bus_name = bus_name;
+        }
+        if let Some(database) = overrides.database {
+            self.database = Some(database);
+        }
+        if let Some(log_level) = overrides.log_level {
+            self.log_level = log_level;
+        }
+        if let Some(delete_after_delivery) = overrides.delete_after_delivery {
+            self.delete_after_delivery = delete_after_delivery;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !is_valid_bus_name(&self.bus_name) {
+            return Err(Error::Config(format!("bus_name \"{}\" is not a valid D-Bus name", self.bus_name)));
+        }
+        self.log_level_filter()?;
+        let t = &self.timeouts;
+        for (name, value) in &[
+            ("timeouts.removed_apps_interval", t.removed_apps_interval),
+            ("timeouts.retry_interval", t.retry_interval),
+            ("timeouts.delivery", t.delivery),
+            ("timeouts.activation", t.activation),
+            ("timeouts.dead_after", t.dead_after),
+            ("timeouts.ping_interval", t.ping_interval),
+            ("timeouts.pong_timeout", t.pong_timeout),
+            ("timeouts.close", t.close),
+            ("timeouts.shutdown", t.shutdown),
+            ("backoff.initial", self.backoff.initial),
+            ("backoff.max", self.backoff.max),
+        ] {
+            check_positive(name, *value)?;
+        }
+        if self.backoff.initial > self.backoff.max {
+            return Err(Error::Config("backoff.initial can't be longer than backoff.max".to_owned()));
+        }
+        if !(self.backoff.multiplier >= 1.0 && self.backoff.multiplier.is_finite()) {
+            return Err(Error::Config(format!("backoff.multiplier must be at least 1, not {}", self.backoff.multiplier)));
+        }
+        if !(0.0..1.0).contains(&self.backoff.jitter) {
+            return Err(Error::Config(format!("backoff.jitter must be at least 0 and below 1, not {}", self.backoff.jitter)));
+        }
+        Ok(())
+    }
+
+    /// Names of the settings that differ from `running`, but only take effect after a restart.
+    /// `default_account` and `routes` are the ones that can change while running.
+    pub fn restart_required(&self, running: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.bus != running.bus {
+            changed.push("bus");
+        }
+        if self.bus_name != running.bus_name {
+            changed.push("bus_name");
+        }
+        if self.database != running.database {
+            changed.push("database");
+        }
+        if self.log_level != running.log_level {
+            changed.push("log_level");
+        }
+        if self.delete_after_delivery != running.delete_after_delivery {
+            changed.push("delete_after_delivery");
+        }
+        if self.timeouts != running.timeouts {
+            changed.push("timeouts");
+        }
+        if self.backoff != running.backoff {
+            changed.push("backoff");
+        }
+        changed
+    }
+
+    /// Checks the account names, and that the default account and routes refer to existing ones
+    pub fn validate_accounts(&self, accounts: &[Account]) -> Result<(), Error> {
+        if accounts.is_empty() {
+            return Err(Error::Config("login.json doesn't contain any accounts".to_owned()));
+        }
+        for (i, account) in accounts.iter().enumerate() {
+            if account.name.is_empty() {
+                return Err(Error::Config("Account names in login.json can't be empty".to_owned()));
+            }
+            if accounts[..i].iter().any(|a| a.name == account.name) {
+                return Err(Error::Config(format!("Account {} is in login.json more than once", account.name)));
+            }
+        }
+        let names = self.default_account.iter().map(|name| ("default_account", name))
+            .chain(self.routes.iter().map(|route| ("routes", &route.account)));
+        for (setting, name) in names {
+            if !accounts.iter().any(|a| &a.name == name) {
+                return Err(Error::Config(format!("{} refers to account {}, which isn't in login.json", setting, name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The account new registrations of the app go to
+    pub fn account_for<'a>(&'a self, accounts: &'a [Account], appid: &str) -> &'a str {
+        self.routes.iter()
+            .find(|route| route.matches(appid))
+            .map(|route| route.account.as_str())
+            .or(self.default_account.as_deref())
+            .unwrap_or_else(|| accounts[0].name.as_str())
+    }
+
+    pub fn log_level_filter(&self) -> Result<log::LevelFilter, Error> {
+        log::LevelFilter::from_str(&self.log_level)
+            .map_err(|_| Error::Config(format!("log_level must be one of off, error, warn, info, debug or trace, not \"{}\"", self.log_level)))
+    }
+
+    pub fn database_path(&self, config_dir: &Path) -> PathBuf {
+        self.database.clone().unwrap_or_else(|| config_dir.join("database.db"))
+    }
+}
+
+/// Converts a validated number of seconds
+pub fn seconds(value: f64) -> Duration {
+    Duration::from_secs_f64(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoginFile;
+
+    fn account(name: &str) -> Account {
+        Account {
+            name: name.to_owned(),
+            login: LoginFile {
+                gotify_base_url: "https://gotify.example.com".to_owned(),
+                gotify_device_token: "token".to_owned(),
+                created_client: true,
+            },
+        }
+    }
+
+    fn route(appid: &str, account: &str) -> Route {
+        Route { appid: appid.to_owned(), account: account.to_owned() }
+    }
+
+    fn config_error(result: Result<(), Error>) -> String {
+        match result {
+            Err(Error::Config(message)) => message,
+            other => panic!("Expected a configuration error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_the_defaults() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn explains_invalid_settings() {
+        let mut config = Config::default();
+        config.bus_name = "not a name".to_owned();
+        assert_eq!(config_error(config.validate()), "bus_name \"not a name\" is not a valid D-Bus name");
+
+        let mut config = Config::default();
+        config.log_level = "loud".to_owned();
+        assert_eq!(config_error(config.validate()),
+            "log_level must be one of off, error, warn, info, debug or trace, not \"loud\"");
+
+        let mut config = Config::default();
+        config.timeouts.dead_after = 0.0;
+        assert_eq!(config_error(config.validate()), "timeouts.dead_after must be a positive number of seconds, not 0");
+
+        let mut config = Config::default();
+        config.timeouts.delivery = f64::NAN;
+        assert_eq!(config_error(config.validate()), "timeouts.delivery must be a positive number of seconds, not NaN");
+
+        let mut config = Config::default();
+        config.backoff.initial = 600.0;
+        assert_eq!(config_error(config.validate()), "backoff.initial can't be longer than backoff.max");
+
+        let mut config = Config::default();
+        config.backoff.multiplier = 0.5;
+        assert_eq!(config_error(config.validate()), "backoff.multiplier must be at least 1, not 0.5");
+
+        let mut config = Config::default();
+        config.backoff.jitter = 1.0;
+        assert_eq!(config_error(config.validate()), "backoff.jitter must be at least 0 and below 1, not 1");
+    }
+
+    #[test]
+    fn lists_the_changes_that_need_a_restart() {
+        let running = Config::default();
+        let mut config = running.clone();
+        config.default_account = Some("work".to_owned());
+        config.routes.push(route("org.example.*", "work"));
+        assert!(config.restart_required(&running).is_empty());
+
+        config.bus = Bus::System;
+        config.log_level = "debug".to_owned();
+        config.timeouts.retry_interval = 60.0;
+        config.backoff.jitter = 0.0;
+        assert_eq!(config.restart_required(&running), vec!["bus", "log_level", "timeouts", "backoff"]);
+    }
+
+    #[test]
+    fn routes_apps_to_accounts() {
+        let accounts = vec![account("personal"), account("work")];
+        let mut config = Config::default();
+        assert_eq!(config.account_for(&accounts, "org.example.Chat"), "personal");
+
+        config.default_account = Some("work".to_owned());
+        assert_eq!(config.account_for(&accounts, "org.example.Chat"), "work");
+
+        // The first matching route wins over later ones and the default account
+        config.routes = vec![route("org.example.Chat", "personal"), route("org.example.*", "work")];
+        config.default_account = None;
+        assert_eq!(config.account_for(&accounts, "org.example.Chat"), "personal");
+        assert_eq!(config.account_for(&accounts, "org.example.Mail"), "work");
+        assert_eq!(config.account_for(&accounts, "org.other.Mail"), "personal");
+    }
+
+    #[test]
+    fn checks_the_accounts_settings_refer_to() {
+        let accounts = vec![account("personal"), account("work")];
+        let mut config = Config::default();
+        config.validate_accounts(&accounts).unwrap();
+
+        assert_eq!(config_error(config.validate_accounts(&[])), "login.json doesn't contain any accounts");
+        assert_eq!(config_error(config.validate_accounts(&[account("work"), account("work")])),
+            "Account work is in login.json more than once");
+
+        config.routes.push(route("org.example.*", "school"));
+        assert_eq!(config_error(config.validate_accounts(&accounts)),
+            "routes refers to account school, which isn't in login.json");
+    }
+
+    /// The only test touching the environment, which the tests share
+    #[test]
+    fn prefers_the_command_line_over_the_environment_over_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "bus_name = \"org.example.File\"\nlog_level = \"warn\"\ndelete_after_delivery = false\n").unwrap();
+        std::env::set_var("UNIFIEDPUSH_GOTIFY_BUS_NAME", "org.example.Env");
+        std::env::set_var("UNIFIEDPUSH_GOTIFY_LOG_LEVEL", "debug");
+        let source = Source {
+            path,
+            overrides: Overrides { log_level: Some("trace".to_owned()), ..Overrides::default() },
+        };
+
+        let config = source.load();
+        std::env::remove_var("UNIFIEDPUSH_GOTIFY_BUS_NAME");
+        std::env::remove_var("UNIFIEDPUSH_GOTIFY_LOG_LEVEL");
+        let config = config.unwrap();
+
+        assert_eq!(config.bus_name, "org.example.Env");
+        assert_eq!(config.log_level, "trace");
+        assert!(!config.delete_after_delivery);
+        assert_eq!(config.bus, Bus::Session);
+    }
+
+    #[test]
+    fn explains_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "colour = \"blue\"\n").unwrap();
+        match Config::load(&path) {
+            Err(Error::Config(message)) => assert!(
+                message.starts_with(&format!("{}: unknown field `colour`", path.display())), "Unexpected message: {}", message),
+            other => panic!("Expected a configuration error, got {:?}", other),
+        }
+    }
+}
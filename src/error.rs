@@ -0,0 +1,111 @@
+use std::fmt;
+
+use r2d2_sqlite::rusqlite;
+
+use crate::gotify::GotifyError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A query on the connection database failed
+    Database(rusqlite::Error),
+    /// No database connection could be taken from the pool
+    Pool(r2d2::Error),
+    /// A Gotify request failed, or the server answered with an error status
+    Gotify(GotifyError),
+    Json(serde_json::Error),
+    DBus(zbus::Error),
+    /// A call to the message bus itself failed
+    DBusCall(zbus::fdo::Error),
+    Io(std::io::Error),
+    /// The configuration or login file is missing or invalid
+    Config(String),
+    /// The database was created by a newer version of the daemon, with the given schema version
+    UnsupportedSchema(i32),
+    /// The Secret Service refused to store or hand out a token
+    SecretService(String),
+    /// The Gotify receivers didn't stop within the shutdown timeout
+    ShutdownTimedOut,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Database(e) => write!(f, "Database error: {}", e),
+            Error::Pool(e) => write!(f, "Database connection error: {}", e),
+            Error::Gotify(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "Invalid JSON: {}", e),
+            Error::DBus(e) => write!(f, "D-Bus error: {}", e),
+            Error::DBusCall(e) => write!(f, "D-Bus call failed: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Config(e) => write!(f, "Invalid configuration: {}", e),
+            Error::UnsupportedSchema(version) => write!(f,
+                "Database schema version {} is newer than the supported version {}", version, crate::migrations::SCHEMA_VERSION),
+            Error::SecretService(e) => write!(f, "Secret Service error: {}", e),
+            Error::ShutdownTimedOut => write!(f, "Shutdown timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Database(e) => Some(e),
+            Error::Pool(e) => Some(e),
+            Error::Gotify(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::DBus(e) => Some(e),
+            Error::DBusCall(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Config(_) | Error::UnsupportedSchema(_) | Error::SecretService(_) | Error::ShutdownTimedOut => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Error::Database(error)
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(error: r2d2::Error) -> Self {
+        Error::Pool(error)
+    }
+}
+
+impl From<GotifyError> for Error {
+    fn from(error: GotifyError) -> Self {
+        Error::Gotify(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
+
+impl From<zbus::Error> for Error {
+    fn from(error: zbus::Error) -> Self {
+        Error::DBus(error)
+    }
+}
+
+/// Reading a message's header or body fails with these
+impl From<zbus::MessageError> for Error {
+    fn from(error: zbus::MessageError) -> Self {
+        Error::DBus(error.into())
+    }
+}
+
+impl From<zbus::fdo::Error> for Error {
+    fn from(error: zbus::fdo::Error) -> Self {
+        Error::DBusCall(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
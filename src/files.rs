@@ -0,0 +1,116 @@
+use std::fs::{self, DirBuilder, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use log::warn;
+
+use crate::Error;
+
+const PRIVATE_DIR_MODE: u32 = 0o700;
+const PRIVATE_FILE_MODE: u32 = 0o600;
+
+/// Creates the directory and its missing parents, and makes the directory accessible only by the user
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(PRIVATE_DIR_MODE).create(path)?;
+    // The mode only applies to directories that didn't exist yet
+    fs::set_permissions(path, Permissions::from_mode(PRIVATE_DIR_MODE))
+}
+
+/// Creates an empty file only the user can read and write, unless it exists
+pub fn create_private_file(path: &Path) -> io::Result<()> {
+    match OpenOptions::new().write(true).create_new(true).mode(PRIVATE_FILE_MODE).open(path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Replaces the file with one only the user can read and write.
+/// The contents are written to a temporary file next to it, which is renamed over it,
+/// so a crash leaves either the old or the new file but never a truncated one.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = temporary_path(path);
+    let written = OpenOptions::new().write(true).create(true).truncate(true).mode(PRIVATE_FILE_MODE).open(&temporary)
+        .and_then(|mut file| {
+            // A leftover temporary file keeps the mode it was created with
+            file.set_permissions(Permissions::from_mode(PRIVATE_FILE_MODE))?;
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temporary, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written?;
+    // The rename is only durable once the directory holding the file is synced
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    fs::File::open(parent)?.sync_all()
+}
+
+/// Refuses files and directories anyone can change, and warns about ones the group or others can access.
+/// Missing paths are fine, they are created private.
+pub fn check_permissions(path: &Path) -> Result<(), Error> {
+    let mode = match fs::metadata(path) {
+        Ok(metadata) => metadata.permissions().mode(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if mode & 0o002 != 0 {
+        return Err(Error::Config(format!(
+            "{} can be changed by any user (mode {:o}), restrict it with chmod go-rwx", path.display(), mode & 0o777)));
+    }
+    if mode & 0o077 != 0 {
+        warn!("{} can be accessed by other users (mode {:o}), restrict it with chmod go-rwx", path.display(), mode & 0o777);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_mode(dir: &tempfile::TempDir, name: &str, mode: u32) -> PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, b"").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[test]
+    fn refuses_files_anyone_can_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = with_mode(&dir, "login.json", 0o602);
+        match check_permissions(&path) {
+            Err(Error::Config(message)) => assert!(message.contains("can be changed by any user (mode 602)"), "{}", message),
+            other => panic!("Expected a configuration error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_private_readable_and_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_permissions(&with_mode(&dir, "private", 0o600)).is_ok());
+        // Only warned about
+        assert!(check_permissions(&with_mode(&dir, "readable", 0o644)).is_ok());
+        assert!(check_permissions(&dir.path().join("missing")).is_ok());
+    }
+
+    #[test]
+    fn writes_private_files_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = with_mode(&dir, "login.json", 0o644);
+        write_private(&path, b"{}").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"{}");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, PRIVATE_FILE_MODE);
+        assert!(!temporary_path(&path).exists());
+    }
+}
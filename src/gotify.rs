@@ -0,0 +1,253 @@
+use std::fmt;
+
+use serde::{ Serialize, Deserialize };
+use serde::de::DeserializeOwned;
+use reqwest::{Method, RequestBuilder, StatusCode};
+
+use crate::{LoginFile, GotifyApplication};
+
+#[derive(Debug)]
+pub enum GotifyError {
+    /// The request couldn't be sent, or the response couldn't be read or parsed
+    Http(reqwest::Error),
+    /// The server answered with an error status, and the description from the body if it had one
+    Status(StatusCode, Option<String>),
+}
+
+impl fmt::Display for GotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GotifyError::Http(e) => write!(f, "Gotify request failed: {}", e),
+            GotifyError::Status(status, Some(description)) => write!(f, "Gotify server returned {}: {}", status, description),
+            GotifyError::Status(status, None) => write!(f, "Gotify server returned {}", status),
+        }
+    }
+}
+
+impl std::error::Error for GotifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GotifyError::Http(e) => Some(e),
+            GotifyError::Status(_, _) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for GotifyError {
+    fn from(error: reqwest::Error) -> Self {
+        GotifyError::Http(error)
+    }
+}
+
+impl GotifyError {
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, GotifyError::Status(status, _) if *status == StatusCode::UNAUTHORIZED || *status == StatusCode::FORBIDDEN)
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, GotifyError::Status(status, _) if *status == StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct GotifyErrorBody {
+    #[serde(rename = "errorDescription")]
+    error_description: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GotifyMessage {
+    pub id: i32,
+    pub appid: i32,
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GotifyPaging {
+    /// URL of the next (older) page, if there is one
+    pub next: Option<String>,
+    /// Pass as `since` to get the next page
+    pub since: i32,
+    pub size: i32,
+    pub limit: i32,
+}
+
+/// One page of messages, newest first
+#[derive(Deserialize, Debug)]
+pub struct GotifyPagedMessages {
+    pub paging: GotifyPaging,
+    pub messages: Vec<GotifyMessage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GotifyClientInfo {
+    pub id: i32,
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GotifyUser {
+    pub id: i32,
+    pub name: String,
+    pub admin: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GotifyHealth {
+    pub health: String,
+    pub database: String,
+}
+
+#[derive(Serialize)]
+struct ApplicationParams<'a> {
+    name: &'a str,
+    description: &'a str,
+}
+
+#[derive(Serialize)]
+struct MessageParams<'a> {
+    title: &'a str,
+    message: &'a str,
+}
+
+#[derive(Serialize)]
+struct ClientParams<'a> {
+    name: &'a str,
+}
+
+#[derive(Clone)]
+enum Auth {
+    Token(String),
+    Basic(String, String),
+}
+
+/// Client for the parts of the Gotify REST API the distributor uses
+#[derive(Clone)]
+pub struct GotifyClient {
+    client: reqwest::Client,
+    base_url: String,
+    auth: Auth,
+}
+
+impl GotifyClient {
+    /// Authenticates with a client token, like the device token in `login.json`
+    pub fn new(base_url: &str, token: &str) -> Self {
+        GotifyClient {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            auth: Auth::Token(token.to_owned()),
+        }
+    }
+
+    pub fn from_login(login_file: &LoginFile) -> Self {
+        Self::new(&login_file.gotify_base_url, &login_file.gotify_device_token)
+    }
+
+    /// Authenticates with a username and password, which is needed for creating clients
+    pub fn with_basic_auth(base_url: &str, username: &str, password: &str) -> Self {
+        GotifyClient {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            auth: Auth::Basic(username.to_owned(), password.to_owned()),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let builder = self.client.request(method, format!("{}{}", self.base_url, path).as_str());
+        match &self.auth {
+            Auth::Token(token) => builder.header("X-Gotify-Key", token),
+            Auth::Basic(username, password) => builder.basic_auth(username, Some(password)),
+        }
+    }
+
+    async fn send(&self, builder: RequestBuilder) -> Result<reqwest::Response, GotifyError> {
+        let response = builder.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(response)
+        } else {
+            let description = response.json::<GotifyErrorBody>().await.ok().map(|b| b.error_description);
+            Err(GotifyError::Status(status, description))
+        }
+    }
+
+    async fn send_json<T: DeserializeOwned>(&self, builder: RequestBuilder) -> Result<T, GotifyError> {
+        Ok(self.send(builder).await?.json::<T>().await?)
+    }
+
+    pub async fn list_applications(&self) -> Result<Vec<GotifyApplication>, GotifyError> {
+        self.send_json(self.request(Method::GET, "/application")).await
+    }
+
+    pub async fn create_application(&self, name: &str, description: &str) -> Result<GotifyApplication, GotifyError> {
+        self.send_json(self.request(Method::POST, "/application")
+            .json(&ApplicationParams { name, description })).await
+    }
+
+    pub async fn update_application(&self, id: i32, name: &str, description: &str) -> Result<GotifyApplication, GotifyError> {
+        self.send_json(self.request(Method::PUT, &format!("/application/{}", id))
+            .json(&ApplicationParams { name, description })).await
+    }
+
+    pub async fn delete_application(&self, id: i32) -> Result<(), GotifyError> {
+        self.send(self.request(Method::DELETE, &format!("/application/{}", id))).await?;
+        Ok(())
+    }
+
+    /// Lists messages of all applications with ids below `since` (or the newest if `None`)
+    pub async fn list_messages(&self, limit: i32, since: Option<i32>) -> Result<GotifyPagedMessages, GotifyError> {
+        let mut builder = self.request(Method::GET, "/message").query(&[("limit", limit)]);
+        if let Some(since) = since {
+            builder = builder.query(&[("since", since)]);
+        }
+        self.send_json(builder).await
+    }
+
+    /// Lists messages of one application with ids below `since` (or the newest if `None`)
+    pub async fn list_application_messages(&self, appid: i32, limit: i32, since: Option<i32>) -> Result<GotifyPagedMessages, GotifyError> {
+        let mut builder = self.request(Method::GET, &format!("/application/{}/message", appid)).query(&[("limit", limit)]);
+        if let Some(since) = since {
+            builder = builder.query(&[("since", since)]);
+        }
+        self.send_json(builder).await
+    }
+
+    /// Sends a message as the application whose token the client authenticates with
+    pub async fn create_message(&self, title: &str, message: &str) -> Result<GotifyMessage, GotifyError> {
+        self.send_json(self.request(Method::POST, "/message")
+            .json(&MessageParams { title, message })).await
+    }
+
+    pub async fn delete_message(&self, id: i32) -> Result<(), GotifyError> {
+        self.send(self.request(Method::DELETE, &format!("/message/{}", id))).await?;
+        Ok(())
+    }
+
+    pub async fn list_clients(&self) -> Result<Vec<GotifyClientInfo>, GotifyError> {
+        self.send_json(self.request(Method::GET, "/client")).await
+    }
+
+    pub async fn create_client(&self, name: &str) -> Result<GotifyClientInfo, GotifyError> {
+        self.send_json(self.request(Method::POST, "/client")
+            .json(&ClientParams { name })).await
+    }
+
+    pub async fn delete_client(&self, id: i32) -> Result<(), GotifyError> {
+        self.send(self.request(Method::DELETE, &format!("/client/{}", id))).await?;
+        Ok(())
+    }
+
+    pub async fn current_user(&self) -> Result<GotifyUser, GotifyError> {
+        self.send_json(self.request(Method::GET, "/current/user")).await
+    }
+
+    /// Doesn't need authentication
+    pub async fn health(&self) -> Result<GotifyHealth, GotifyError> {
+        self.send_json(self.client.get(format!("{}/health", self.base_url).as_str())).await
+    }
+}
@@ -1,200 +1,1151 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use unifiedpush_gotify_lib::{
+    Error,
     LoginFile,
-    GotifyApplication,
-    get_all_connections
+    DbPendingMessage,
+    DBUS_API_V2,
+    MANAGER_INTERFACE,
+    MANAGER_PATH,
+    DELIVERY_PENDING,
+    DELIVERY_DELIVERED,
+    DELIVERY_REJECTED,
+    get_account_connections,
+    get_app_watermarks,
+    get_pending_messages
 };
+use unifiedpush_gotify_lib::config::{Config, seconds};
+use unifiedpush_gotify_lib::gotify::{GotifyClient, GotifyMessage, GotifyPagedMessages};
 use r2d2_sqlite::SqliteConnectionManager;
-use r2d2_sqlite::rusqlite::params;
+use r2d2_sqlite::rusqlite::{params, OptionalExtension};
+use futures_util::future::FutureExt;
+use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use rand::Rng;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{broadcast, watch};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use url::Url;
-use serde::Deserialize;
+use zvariant::Value;
+use lazy_static::lazy_static;
 use log::{error, warn, info, debug, trace};
 
-#[derive(Deserialize, Debug)]
-struct GotifyMessage {
-    id: i32,
-    appid: i32,
-    message: String,
+use crate::{bus, shutdown};
+use crate::system_events::SystemEvent;
+
+lazy_static! {
+    /// Serializes deliveries to each app from the websocket, catch-up and the retry task, so a message isn't pushed twice.
+    /// Each Gotify application of each account has its own, so a sleeping or slow app doesn't hold up the others.
+    static ref FLUSH_LOCKS: std::sync::Mutex<HashMap<(String, i32), Arc<tokio::sync::Mutex<()>>>> = std::sync::Mutex::new(HashMap::new());
+    /// Apps with new messages that no delivery has started on yet, so a burst of messages for a sleeping app starts one
+    static ref FLUSH_REQUESTS: std::sync::Mutex<HashSet<(String, i32)>> = std::sync::Mutex::new(HashSet::new());
+}
+
+fn flush_lock(account: &str, gotify_id: i32) -> Arc<tokio::sync::Mutex<()>> {
+    FLUSH_LOCKS.lock().unwrap().entry((account.to_owned(), gotify_id)).or_default().clone()
 }
 
-#[derive(Deserialize, Debug)]
-struct GotifyMessagesList {
-    messages: Vec<GotifyMessage>
+/// Notes that the app has new messages, returning false if a delivery that will pick them up is already waiting
+fn request_flush(account: &str, gotify_id: i32) -> bool {
+    FLUSH_REQUESTS.lock().unwrap().insert((account.to_owned(), gotify_id))
 }
 
-async fn send_push(conn: &zbus::azync::Connection, appid: &str, token: &str, data: &str) {
-    if let Ok(_) = conn.send_message(zbus::Message::method(
-        Some("org.unifiedpush.Connector.gotify"), 
-        Some(appid),
-        "/org/unifiedpush/Connector",
-        Some("org.unifiedpush.Connector1"),
-        "Message",
-        &(token, data, "")).unwrap()).await {
-        eprintln!("Push message: {}: {}", appid, data);
+/// Waits until the deliveries running for the account are done
+async fn wait_for_flushes(account: &str) {
+    let locks: Vec<_> = FLUSH_LOCKS.lock().unwrap().iter()
+        .filter(|((a, _), _)| a == account)
+        .map(|(_, lock)| lock.clone())
+        .collect();
+    for lock in locks {
+        let _ = lock.lock().await;
+    }
+}
+
+/// `StartServiceByName` reply when the name already has an owner
+const DBUS_START_REPLY_ALREADY_RUNNING: u32 = 2;
+
+/// Number of messages requested per page while catching up on missed messages
+const CATCH_UP_PAGE_SIZE: i32 = 100;
+
+#[derive(Debug, PartialEq)]
+enum Delivery {
+    /// The connector app acknowledged the message
+    Delivered,
+    /// The app isn't running and the bus couldn't activate it
+    NoSuchApp,
+    /// The app didn't reply in time, or the bus connection failed
+    Timeout,
+    /// The app replied with the given D-Bus error
+    AppError(String),
+}
+
+impl Delivery {
+    fn from_reply(reply: Result<zbus::Result<zbus::Message>, tokio::time::error::Elapsed>) -> Self {
+        match reply {
+            Ok(Ok(_)) => Delivery::Delivered,
+            Ok(Err(zbus::Error::MethodError(name, _, _))) => match name.as_str() {
+                "org.freedesktop.DBus.Error.ServiceUnknown"
+                    | "org.freedesktop.DBus.Error.NameHasNoOwner" => Delivery::NoSuchApp,
+                "org.freedesktop.DBus.Error.NoReply"
+                    | "org.freedesktop.DBus.Error.Timeout" => Delivery::Timeout,
+                _ => Delivery::AppError(name),
+            },
+            Ok(Err(_)) | Err(_) => Delivery::Timeout,
+        }
+    }
+
+    /// Whether the app is reachable but no longer implements the connector interface
+    fn is_dead_registration(&self) -> bool {
+        match self {
+            Delivery::AppError(name) => matches!(name.as_str(),
+                "org.freedesktop.DBus.Error.UnknownObject"
+                    | "org.freedesktop.DBus.Error.UnknownInterface"
+                    | "org.freedesktop.DBus.Error.UnknownMethod"),
+            _ => false,
+        }
+    }
+
+    /// Whether the registration should be dropped, because the app no longer takes pushes or has been
+    /// unreachable since `unreachable_since` for longer than `dead_after`. Times are Unix times.
+    fn is_dead_app(&self, unreachable_since: Option<i64>, now: i64, dead_after: Duration) -> bool {
+        match self {
+            Delivery::NoSuchApp => now - unreachable_since.unwrap_or(now) >= dead_after.as_secs() as i64,
+            _ => self.is_dead_registration(),
+        }
+    }
+}
+
+/// Makes sure the connector app owns its bus name, asking the bus to activate it if it doesn't.
+/// Returns whether or not the app is running, or the error if the bus couldn't be asked.
+async fn activate_app(conn: &zbus::azync::Connection, config: &ReceiverConfig, appid: &str) -> zbus::Result<bool> {
+    if bus::name_has_owner(conn, appid).await? {
+        return Ok(true);
+    }
+
+    // Subscribe before asking for activation, so the owner change can't be missed
+    let mut owner_changes = bus::owner_changes();
+    bus::watch_name(conn, appid).await?;
+    debug!("{} isn't running, activating it", appid);
+    let activated = bus::start_service_by_name(conn, appid).await;
+    let appeared = match &activated {
+        Ok(DBUS_START_REPLY_ALREADY_RUNNING) => true,
+        Ok(_) => tokio::time::timeout(config.activation_timeout, async {
+            loop {
+                match owner_changes.recv().await {
+                    Ok((name, new_owner)) if name == appid && !new_owner.is_empty() => return true,
+                    Err(broadcast::error::RecvError::Closed) => return false,
+                    _ => {}
+                }
+            }
+        }).await.unwrap_or(false),
+        Err(_) => false,
+    };
+    if let Err(e) = bus::unwatch_name(conn, appid).await {
+        warn!("Failed to unsubscribe from NameOwnerChanged: {}", e);
+    }
+    if let Err(e) = activated {
+        info!("Couldn't activate {}: {}", appid, e);
+        return Ok(false);
+    }
+    // The name may have been claimed before the activation request returned
+    Ok(appeared || bus::name_has_owner(conn, appid).await?)
+}
+
+/// Expects the app to be running, `activate_app` wakes sleeping apps up beforehand.
+async fn send_push(conn: &zbus::azync::Connection, config: &ReceiverConfig, dbus_version: i32, appid: &str, token: &str, data: &str) -> Delivery {
+    let reply = if dbus_version == DBUS_API_V2 {
+        let mut args = HashMap::new();
+        args.insert("token", Value::from(token));
+        args.insert("message", Value::from(data.as_bytes()));
+        tokio::time::timeout(config.delivery_timeout, bus::call(
+            conn,
+            appid,
+            "/org/unifiedpush/Connector",
+            "org.unifiedpush.Connector2",
+            "Message",
+            &args)).await
+    } else {
+        tokio::time::timeout(config.delivery_timeout, bus::call(
+            conn,
+            appid,
+            "/org/unifiedpush/Connector",
+            "org.unifiedpush.Connector1",
+            "Message",
+            &(token, data, ""))).await
+    };
+    let delivery = Delivery::from_reply(reply);
+    if delivery == Delivery::Delivered {
+        info!("Push message: {}: {}", appid, data);
     } else {
-        eprintln!("Sending Message failed: {}: {}", appid, data)
+        warn!("Sending Message to {} failed: {:?}", appid, delivery);
     }
+    delivery
 }
 
-async fn send_unregister(conn: &zbus::azync::Connection, appid: &str, token: &str) {
-    if let Ok(_) = conn.send_message(zbus::Message::method(
-        Some("org.unifiedpush.Distributor.gotify"),
-        Some(appid),
-        "/org/unifiedpush/Connector",
-        Some("org.unifiedpush.Connector1"),
-        "Unregister",
-        &(token)).unwrap()).await {
-        eprintln!("Unregistered from Gotify: {}", appid);
+async fn send_unregister(conn: &zbus::azync::Connection, config: &ReceiverConfig, dbus_version: i32, appid: &str, token: &str) -> Result<(), Error> {
+    let message = if dbus_version == DBUS_API_V2 {
+        let mut args = HashMap::new();
+        args.insert("token", Value::from(token));
+        zbus::Message::method(
+            Some(config.bus_name.as_str()),
+            Some(appid),
+            "/org/unifiedpush/Connector",
+            Some("org.unifiedpush.Connector2"),
+            "Unregistered",
+            &args)?
     } else {
-        eprintln!("Sending Unregister failed: {}", appid);
+        zbus::Message::method(
+            Some(config.bus_name.as_str()),
+            Some(appid),
+            "/org/unifiedpush/Connector",
+            Some("org.unifiedpush.Connector1"),
+            "Unregistered",
+            &(token))?
+    };
+    conn.send_message(message).await?;
+    info!("Unregistered from Gotify: {}", appid);
+    Ok(())
+}
+
+/// Unregisters the apps whose Gotify application was deleted on the server
+async fn unregister_removed_apps(
+    sqlite_pool: &r2d2::Pool<SqliteConnectionManager>,
+    dbus_conn: &'static zbus::azync::Connection,
+    gotify: &GotifyClient,
+    config: &ReceiverConfig) -> Result<(), Error> {
+    let gotify_connections = gotify.list_applications().await?;
+    for connection in get_account_connections(sqlite_pool, &config.account)? {
+        if gotify_connections.iter().any(|a| a.id == connection.gotify_id) {
+            continue;
+        }
+        if let Err(e) = send_unregister(dbus_conn, config, connection.dbus_version, &connection.appid, &connection.token).await {
+            error!("Failed to send unregistered to {}: {}", connection.appid, e);
+        }
+        sqlite_pool.get()?.execute("DELETE FROM connections WHERE account=? AND gotify_id=?", params![connection.account, connection.gotify_id])?;
     }
+    Ok(())
 }
 
 async fn check_removed_apps(
     sqlite_pool: Arc<r2d2::Pool<SqliteConnectionManager>>,
     dbus_conn: &'static zbus::azync::Connection,
-    login_file: LoginFile) {
-    let client = reqwest::Client::new();
+    gotify: GotifyClient,
+    config: ReceiverConfig,
+    mut shutdown: watch::Receiver<bool>) {
     loop {
-        let response = client.get(format!("{}/application", &login_file.gotify_base_url).as_str())
-            .header("X-Gotify-Key", &login_file.gotify_device_token)
-            .send().await;
-        if let Ok(r) = response.and_then(|r| Ok(r.json::<Vec<GotifyApplication>>())) {
-            if let Ok(gotify_connections) = r.await {
-                if let Ok(db_connections) = get_all_connections(&sqlite_pool) {
-                    for connection in db_connections {
-                        if let None = gotify_connections.iter().find(|a| a.id == connection.gotify_id) {
-                            send_unregister(dbus_conn, &connection.appid, &connection.token).await;
-                            sqlite_pool.get().map_err(|_|())
-                                .and_then(|c| c.execute("DELETE FROM connections WHERE token=?", &[connection.token]).map_err(|_|()));
-                        }
-                    }
-                }
+        if let Err(e) = unregister_removed_apps(&sqlite_pool, dbus_conn, &gotify, &config).await {
+            warn!("Failed to check for removed apps: {}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(config.removed_apps_interval) => {}
+            _ = shutdown::requested(&mut shutdown) => return,
+        }
+    }
+}
+
+/// Records that every message of the Gotify application up to `message_id` has been queued
+fn update_watermark(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str, gotify_id: i32, message_id: i32) -> Result<(), Error> {
+    let c = pool.get()?;
+    c.execute("INSERT OR IGNORE INTO app_watermarks (account, gotify_id, message_id) VALUES (?, ?, ?)", params![account, gotify_id, message_id])?;
+    c.execute("UPDATE app_watermarks SET message_id = MAX(message_id, ?) WHERE account = ? AND gotify_id = ?", params![message_id, account, gotify_id])?;
+    Ok(())
+}
+
+enum Queued {
+    /// The message doesn't belong to a UP connection
+    NotPush,
+    /// The message is waiting for delivery
+    Pending,
+    /// The message was delivered before, but deleting it from the server failed
+    AlreadyDelivered,
+    /// The message is at or below the app's watermark, so it was handled before
+    AlreadyHandled,
+}
+
+/// Stores the message in the delivery queue if it belongs to a UP connection.
+fn queue_message(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str, message: &GotifyMessage) -> Result<Queued, Error> {
+    let conn = pool.get()?;
+    let is_push = conn.query_row(
+        "SELECT gotify_id FROM connections WHERE account = ? AND gotify_id = ?",
+        params![account, message.appid],
+        |r| r.get::<_, i32>(0)
+    ).optional()?.is_some();
+    if !is_push {
+        return Ok(Queued::NotPush);
+    }
+    match conn.query_row("SELECT state FROM deliveries WHERE account = ? AND message_id = ?", params![account, message.id], |r| r.get::<_, String>(0)).optional()? {
+        Some(state) if state == DELIVERY_PENDING => return Ok(Queued::Pending),
+        Some(_) => return Ok(Queued::AlreadyDelivered),
+        None => {}
+    }
+    // The websocket can repeat messages that catch-up already handled
+    if let Some(watermark) = conn.query_row("SELECT message_id FROM app_watermarks WHERE account = ? AND gotify_id = ?", params![account, message.appid], |r| r.get::<_, i32>(0)).optional()? {
+        if message.id <= watermark {
+            return Ok(Queued::AlreadyHandled);
+        }
+    }
+    conn.execute(
+        "INSERT INTO deliveries (account, message_id, gotify_id, data) VALUES (?, ?, ?, ?)",
+        params![account, message.id, message.appid, message.message])?;
+    Ok(Queued::Pending)
+}
+
+fn set_delivery_state(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str, message_id: i32, state: &str) -> Result<(), Error> {
+    pool.get()?.execute("UPDATE deliveries SET state = ? WHERE account = ? AND message_id = ?", params![state, account, message_id])?;
+    Ok(())
+}
+
+fn forget_delivery(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str, message_id: i32) -> Result<(), Error> {
+    pool.get()?.execute("DELETE FROM deliveries WHERE account = ? AND message_id = ?", params![account, message_id])?;
+    Ok(())
+}
+
+/// Records the outcome of a delivery, and deletes the message from the server if configured to.
+/// The delivery is forgotten once the server no longer has the message.
+async fn finish_delivery(
+    pool: &r2d2::Pool<SqliteConnectionManager>,
+    gotify: &GotifyClient,
+    config: &ReceiverConfig,
+    message_id: i32,
+    state: &str) {
+    if !config.delete_after_delivery {
+        // The watermark keeps the message from being delivered again
+        if let Err(e) = forget_delivery(pool, &config.account, message_id) {
+            error!("Failed to remove message {} from the delivery queue: {}", message_id, e);
+        }
+        return;
+    }
+    if let Err(e) = set_delivery_state(pool, &config.account, message_id, state) {
+        error!("Failed to mark message {} as {}: {}", message_id, state, e);
+    }
+    if delete_message(message_id, gotify).await {
+        if let Err(e) = forget_delivery(pool, &config.account, message_id) {
+            error!("Failed to remove message {} from the delivery queue: {}", message_id, e);
+        }
+    }
+}
+
+/// Messages that were delivered or rejected, but couldn't be deleted from the server yet
+fn get_undeleted_messages(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str) -> Result<Vec<i32>, Error> {
+    let conn = pool.get()?;
+    let mut s = conn.prepare("SELECT message_id FROM deliveries WHERE account = ? AND state IN (?, ?) ORDER BY message_id")?;
+    let mut rows = s.query(params![account, DELIVERY_DELIVERED, DELIVERY_REJECTED])?;
+
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        result.push(row.get(0)?);
+    }
+
+    Ok(result)
+}
+
+/// Deletes the finished deliveries from the server again. Catch-up doesn't see these messages again,
+/// as they're below the watermark, so without this they'd stay on the server and in the queue.
+async fn retry_deletions(pool: &r2d2::Pool<SqliteConnectionManager>, gotify: &GotifyClient, config: &ReceiverConfig) {
+    let undeleted = match get_undeleted_messages(pool, &config.account) {
+        Ok(undeleted) => undeleted,
+        Err(e) => {
+            error!("Failed to read delivery queue: {}", e);
+            return;
+        }
+    };
+    for message_id in undeleted {
+        // Messages are kept on the server if deleting was turned off since
+        if !config.delete_after_delivery || delete_message(message_id, gotify).await {
+            if let Err(e) = forget_delivery(pool, &config.account, message_id) {
+                error!("Failed to remove message {} from the delivery queue: {}", message_id, e);
             }
         }
-        tokio::time::sleep(std::time::Duration::from_secs(120)).await;
     }
 }
 
-fn update_last_seen(pool: &r2d2::Pool<SqliteConnectionManager>, message_id: i32) {
-    if let Ok(c) = pool.get() {
-        if let Err(QueryReturnedNoRows) = c.query_row("SELECT message_id FROM last_seen_message", params![], |r| r.get::<_, i32>(0)) {
-            c.execute("INSERT INTO last_seen_message VALUES (?)", params![message_id]);
+fn remove_orphaned_deliveries(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str) -> Result<(), Error> {
+    let conn = pool.get()?;
+    conn.execute("DELETE FROM deliveries WHERE account = ?1 AND gotify_id NOT IN (SELECT gotify_id FROM connections WHERE account = ?1)", &[account])?;
+    conn.execute("DELETE FROM app_watermarks WHERE account = ?1 AND gotify_id NOT IN (SELECT gotify_id FROM connections WHERE account = ?1)", &[account])?;
+    Ok(())
+}
+
+/// Tries to deliver the queued messages of one Gotify application in the order they were received,
+/// stopping at the first that fails to keep them in order. Acknowledged messages are deleted from the Gotify server.
+/// Failed attempts are only counted when `retrying`, so messages arriving for a sleeping app don't add to them.
+async fn flush_app(
+    pool: Arc<r2d2::Pool<SqliteConnectionManager>>,
+    dbus_connection: &'static zbus::azync::Connection,
+    gotify: GotifyClient,
+    config: ReceiverConfig,
+    gotify_id: i32,
+    retrying: bool) {
+    let lock = flush_lock(&config.account, gotify_id);
+    let _guard = lock.lock().await;
+    // Everything queued so far is read below
+    FLUSH_REQUESTS.lock().unwrap().remove(&(config.account.clone(), gotify_id));
+
+    let pending = match get_pending_messages(&pool, &config.account) {
+        Ok(pending) => pending.into_iter().filter(|m| m.gotify_id == gotify_id),
+        Err(e) => {
+            error!("Failed to read delivery queue: {}", e);
+            return;
+        }
+    };
+
+    let mut running = false;
+    for message in pending {
+        // A sleeping app is woken up first, and its pushes are held until it claims its name
+        if !running {
+            running = match activate_app(dbus_connection, &config, &message.appid).await {
+                Ok(running) => running,
+                Err(e) => {
+                    // The bus didn't answer, which says nothing about the app
+                    warn!("Failed to look up or activate {}: {}", message.appid, e);
+                    return;
+                }
+            };
+        }
+        let delivery = if running {
+            send_push(dbus_connection, &config, message.dbus_version, &message.appid, &message.token, &message.data).await
         } else {
-            c.execute("UPDATE last_seen_message SET message_id = MAX(message_id, ?)", &[message_id]);
+            Delivery::NoSuchApp
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        if delivery.is_dead_app(message.unreachable_since, now, config.dead_after) {
+            warn!("Registration of {} is dead, unregistering", message.appid);
+            remove_registration(&pool, dbus_connection, &gotify, &config, &message).await;
+            return;
+        }
+        match delivery {
+            Delivery::Delivered => {
+                emit_push_delivered(dbus_connection, &config, &message).await;
+                finish_delivery(&pool, &gotify, &config, message.message_id, DELIVERY_DELIVERED).await;
+            }
+            Delivery::AppError(_) => {
+                // An app that rejects a message would reject it again, so it isn't retried
+                finish_delivery(&pool, &gotify, &config, message.message_id, DELIVERY_REJECTED).await;
+            }
+            Delivery::NoSuchApp | Delivery::Timeout => {
+                let attempts = message.attempts + if retrying { 1 } else { 0 };
+                debug!("Delivery of message {} to {} failed, {} retries so far", message.message_id, message.appid, attempts);
+                let unreachable_since = if delivery == Delivery::NoSuchApp { message.unreachable_since.or(Some(now)) } else { None };
+                if let Err(e) = record_failure(&pool, &config.account, message.message_id, attempts, unreachable_since) {
+                    error!("Failed to record delivery attempt of message {}: {}", message.message_id, e);
+                }
+                return;
+            }
         }
     }
 }
 
-/// Returns whether or not the message was a UP push message that we handled.
-async fn handle_message(
+/// Tries to deliver every queued message, delivering to the apps concurrently
+async fn flush_pending_messages(
+    pool: &Arc<r2d2::Pool<SqliteConnectionManager>>,
+    dbus_connection: &'static zbus::azync::Connection,
+    gotify: &GotifyClient,
+    config: &ReceiverConfig) {
+    // Messages for apps that have been unregistered in the meantime can't be delivered anymore
+    if let Err(e) = remove_orphaned_deliveries(pool, &config.account) {
+        error!("Failed to clean up delivery queue: {}", e);
+    }
+
+    let pending = match get_pending_messages(pool, &config.account) {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!("Failed to read delivery queue: {}", e);
+            return;
+        }
+    };
+    let mut apps = Vec::new();
+    for message in pending {
+        if !apps.contains(&message.gotify_id) {
+            apps.push(message.gotify_id);
+        }
+    }
+    futures_util::future::join_all(apps.into_iter()
+        .map(|gotify_id| flush_app(pool.clone(), dbus_connection, gotify.clone(), config.clone(), gotify_id, true))).await;
+}
+
+/// Lets observers on the management interface know the connector app acknowledged a message
+async fn emit_push_delivered(dbus_connection: &'static zbus::azync::Connection, config: &ReceiverConfig, message: &DbPendingMessage) {
+    let body = (message.appid.as_str(), config.account.as_str(), message.message_id);
+    if let Err(e) = dbus_connection.emit_signal(None, MANAGER_PATH, MANAGER_INTERFACE, "PushDelivered", &body).await {
+        warn!("Failed to emit PushDelivered: {}", e);
+    }
+}
+
+/// Stores the number of retries of a message, and since when its app has been unreachable if it is
+fn record_failure(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str, message_id: i32, attempts: i32, unreachable_since: Option<i64>) -> Result<(), Error> {
+    pool.get()?.execute(
+        "UPDATE deliveries SET attempts = ?, unreachable_since = ? WHERE account = ? AND message_id = ?",
+        params![attempts, unreachable_since, account, message_id])?;
+    Ok(())
+}
+
+fn delete_registration(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str, gotify_id: i32) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM connections WHERE account = ? AND gotify_id = ?", params![account, gotify_id])?;
+    tx.execute("DELETE FROM deliveries WHERE account = ? AND gotify_id = ?", params![account, gotify_id])?;
+    tx.execute("DELETE FROM app_watermarks WHERE account = ? AND gotify_id = ?", params![account, gotify_id])?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Deletes the Gotify application of a registration whose app is gone, along with its queued messages
+async fn remove_registration(
     pool: &r2d2::Pool<SqliteConnectionManager>,
     dbus_connection: &'static zbus::azync::Connection,
-    message: GotifyMessage) -> bool {
-    if let Ok(conn) = pool.get().map_err(|_|()) {
-        if let Ok(row) = conn.query_row(
-                "SELECT * FROM connections WHERE gotify_id = ?",
-                &[message.appid],
-                |r| Ok((r.get_unwrap::<_, String>("appid"), r.get_unwrap::<_, String>("token")))
-        ).map_err(|_|()) {
-            send_push(dbus_connection, &row.0, &row.1, &message.message).await;
-            update_last_seen(pool, message.id);
-            true
-        } else {
-            update_last_seen(pool, message.id);
-            false
+    gotify: &GotifyClient,
+    config: &ReceiverConfig,
+    message: &DbPendingMessage) {
+    if let Err(e) = gotify.delete_application(message.gotify_id).await {
+        error!("Failed to delete Gotify application of {}: {}", message.appid, e);
+    }
+    if let Err(e) = delete_registration(pool, &config.account, message.gotify_id) {
+        error!("Failed to delete registration of {}: {}", message.appid, e);
+    }
+    if let Err(e) = send_unregister(dbus_connection, config, message.dbus_version, &message.appid, &message.token).await {
+        error!("Failed to send unregistered to {}: {}", message.appid, e);
+    }
+}
+
+async fn retry_pending_messages(
+    sqlite_pool: Arc<r2d2::Pool<SqliteConnectionManager>>,
+    dbus_connection: &'static zbus::azync::Connection,
+    gotify: GotifyClient,
+    config: ReceiverConfig,
+    mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(config.retry_interval) => {}
+            _ = shutdown::requested(&mut shutdown) => return,
+        }
+        flush_pending_messages(&sqlite_pool, dbus_connection, &gotify, &config).await;
+        retry_deletions(&sqlite_pool, &gotify, &config).await;
+    }
+}
+
+/// Queues the message if it is a UP push message, and tries to deliver it.
+async fn handle_message(
+    pool: &Arc<r2d2::Pool<SqliteConnectionManager>>,
+    dbus_connection: &'static zbus::azync::Connection,
+    gotify: &GotifyClient,
+    config: &ReceiverConfig,
+    message: GotifyMessage) {
+    let queued = match queue_message(pool, &config.account, &message) {
+        Ok(queued) => queued,
+        Err(e) => {
+            error!("Failed to queue message {}: {}", message.id, e);
+            return;
+        }
+    };
+    match queued {
+        Queued::NotPush | Queued::AlreadyHandled => return,
+        Queued::Pending | Queued::AlreadyDelivered => {}
+    }
+    let body = (config.account.as_str(), message.appid, message.id);
+    if let Err(e) = dbus_connection.emit_signal(None, MANAGER_PATH, MANAGER_INTERFACE, "MessageReceived", &body).await {
+        warn!("Failed to emit MessageReceived: {}", e);
+    }
+    if let Err(e) = update_watermark(pool, &config.account, message.appid, message.id) {
+        error!("Failed to update watermark of application {}: {}", message.appid, e);
+    }
+    if let Queued::Pending = queued {
+        // Waiting for a sleeping app mustn't hold up reading the stream
+        if request_flush(&config.account, message.appid) {
+            tokio::spawn(flush_app(pool.clone(), dbus_connection, gotify.clone(), config.clone(), message.appid, false));
+        }
+    } else if delete_message(message.id, gotify).await {
+        if let Err(e) = forget_delivery(pool, &config.account, message.id) {
+            error!("Failed to remove message {} from the delivery queue: {}", message.id, e);
         }
-    } else {
-        false
     }
 }
 
+/// Returns whether or not the message is gone from the server
 async fn delete_message(
     message_id: i32,
-    login_file: &LoginFile
-) {
-    let client = reqwest::Client::new();
-    client.delete(format!("{}/message/{}", login_file.gotify_base_url, message_id).as_str())
-        .header("X-Gotify-Key", &login_file.gotify_device_token)
-        .send().await;
+    gotify: &GotifyClient
+) -> bool {
+    match gotify.delete_message(message_id).await {
+        Ok(()) => true,
+        Err(e) if e.is_not_found() => true,
+        Err(e) => {
+            warn!("Failed to delete message {} from Gotify: {}", message_id, e);
+            false
+        }
+    }
+}
+
+/// Delivers the messages of one Gotify application that are newer than its watermark
+async fn catch_up_app(
+    pool: &Arc<r2d2::Pool<SqliteConnectionManager>>,
+    dbus_connection: &'static zbus::azync::Connection,
+    gotify: &GotifyClient,
+    config: &ReceiverConfig,
+    gotify_id: i32,
+    watermark: i32) {
+    // Gotify pages from the newest message backwards. Walk back until the watermark,
+    // keeping only the cursor of each page so memory use doesn't grow with the backlog.
+    let mut cursors = vec![None];
+    let oldest_page = loop {
+        let page = match gotify.list_application_messages(gotify_id, CATCH_UP_PAGE_SIZE, *cursors.last().unwrap()).await {
+            Ok(page) => page,
+            Err(e) => {
+                warn!("Failed to fetch missed messages of application {}: {}", gotify_id, e);
+                return;
+            }
+        };
+        if cursors.len() == 1 {
+            // Newer messages arrive through the stream. Bounding the second fetch of the newest page
+            // keeps it from sliding up when they do, which would skip its oldest messages.
+            cursors[0] = page.messages.iter().map(|m| m.id).max().map(|newest| newest + 1);
+        }
+        if page.paging.next.is_none() || page.messages.iter().any(|m| m.id <= watermark) {
+            break page;
+        }
+        cursors.push(Some(page.paging.since));
+    };
+    debug!("Catching up on {} pages of messages for application {}", cursors.len(), gotify_id);
+
+    // Deliver oldest first, so an interrupted catch-up only leaves newer messages for next time
+    cursors.pop();
+    handle_page(pool, dbus_connection, gotify, config, watermark, oldest_page).await;
+    for cursor in cursors.into_iter().rev() {
+        match gotify.list_application_messages(gotify_id, CATCH_UP_PAGE_SIZE, cursor).await {
+            Ok(page) => handle_page(pool, dbus_connection, gotify, config, watermark, page).await,
+            Err(e) => {
+                warn!("Failed to fetch missed messages of application {}: {}", gotify_id, e);
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_page(
+    pool: &Arc<r2d2::Pool<SqliteConnectionManager>>,
+    dbus_connection: &'static zbus::azync::Connection,
+    gotify: &GotifyClient,
+    config: &ReceiverConfig,
+    watermark: i32,
+    mut page: GotifyPagedMessages) {
+    page.messages.sort_by_key(|m| m.id);
+    for message in page.messages {
+        if message.id > watermark {
+            handle_message(pool, dbus_connection, gotify, config, message).await;
+        }
+    }
 }
 
 async fn check_for_missed_messages(
-    pool: &r2d2::Pool<SqliteConnectionManager>,
+    pool: &Arc<r2d2::Pool<SqliteConnectionManager>>,
     dbus_connection: &'static zbus::azync::Connection,
-    login_file: LoginFile) {
-    info!("Checking for missed messages");
-    if let Some(last_seen_message) = pool.get().ok().and_then(|c| c.query_row("SELECT message_id FROM last_seen_message", params![], |r| r.get::<_, i32>(0)).ok()) {
-        let client = reqwest::Client::new();
-        let response = client.get(format!("{}/message", &login_file.gotify_base_url).as_str())
-            .header("X-Gotify-Key", &login_file.gotify_device_token)
-            .send().await;
-        if let Ok(r) = response.and_then(|r| Ok(r.json::<GotifyMessagesList>())) {
-            if let Ok(mut messages) = r.await {
-                messages.messages.sort_by_key(|m| m.id);
-                for message in messages.messages {
-                    if message.id > last_seen_message {
-                        let id = message.id;
-                        if handle_message(pool, dbus_connection, message).await {
-                            delete_message(id, &login_file).await;
-                        }
-                    }
+    gotify: &GotifyClient,
+    config: &ReceiverConfig) {
+    info!("Checking for missed messages of account {}", config.account);
+    let connections = match get_account_connections(pool, &config.account) {
+        Ok(connections) => connections,
+        Err(e) => {
+            error!("Failed to read connections: {}", e);
+            return;
+        }
+    };
+    let watermarks = match get_app_watermarks(pool, &config.account) {
+        Ok(watermarks) => watermarks,
+        Err(e) => {
+            error!("Failed to read watermarks: {}", e);
+            return;
+        }
+    };
+    for connection in connections {
+        // Until the first message has been seen, an app gets everything sent since it registered
+        let watermark = watermarks.get(&connection.gotify_id).copied().unwrap_or(connection.first_message_id);
+        catch_up_app(pool, dbus_connection, gotify, config, connection.gotify_id, watermark).await;
+    }
+}
+
+/// Timing and delivery settings of the receiver, from the daemon configuration
+#[derive(Debug, Clone)]
+pub struct ReceiverConfig {
+    /// The Gotify account this receiver delivers messages of
+    pub account: String,
+    pub bus_name: String,
+    pub delete_after_delivery: bool,
+    pub removed_apps_interval: Duration,
+    pub retry_interval: Duration,
+    /// How long a connector app gets to acknowledge a `Message` call
+    pub delivery_timeout: Duration,
+    /// How long a sleeping connector app gets to claim its bus name after being activated
+    pub activation_timeout: Duration,
+    /// How long an app can stay unreachable before its registration is dropped
+    pub dead_after: Duration,
+    /// Delay before the first reconnection attempt
+    pub backoff_initial: Duration,
+    pub backoff_max: Duration,
+    pub backoff_multiplier: f64,
+    /// Fraction by which each delay is randomly varied, so clients don't reconnect in lockstep
+    pub backoff_jitter: f64,
+    pub ping_interval: Duration,
+    /// The connection is dead if nothing arrives for this long after a ping is due
+    pub pong_timeout: Duration,
+    /// How long Gotify gets to answer our close frame on shutdown
+    pub close_timeout: Duration,
+}
+
+impl ReceiverConfig {
+    pub fn from_config(config: &Config, account: &str) -> Self {
+        ReceiverConfig {
+            account: account.to_owned(),
+            bus_name: config.bus_name.clone(),
+            delete_after_delivery: config.delete_after_delivery,
+            removed_apps_interval: seconds(config.timeouts.removed_apps_interval),
+            retry_interval: seconds(config.timeouts.retry_interval),
+            delivery_timeout: seconds(config.timeouts.delivery),
+            activation_timeout: seconds(config.timeouts.activation),
+            dead_after: seconds(config.timeouts.dead_after),
+            backoff_initial: seconds(config.backoff.initial),
+            backoff_max: seconds(config.backoff.max),
+            backoff_multiplier: config.backoff.multiplier,
+            backoff_jitter: config.backoff.jitter,
+            ping_interval: seconds(config.timeouts.ping_interval),
+            pong_timeout: seconds(config.timeouts.pong_timeout),
+            close_timeout: seconds(config.timeouts.close),
+        }
+    }
+}
+
+/// A connection that stayed up this long resets the backoff
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    /// Connected, delivering messages that arrived while disconnected
+    CatchingUp,
+    Live,
+    /// Waiting before the next connection attempt
+    Backoff,
+}
+
+impl ConnectionState {
+    /// The state as reported on the management interface
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::CatchingUp => "catching-up",
+            ConnectionState::Live => "live",
+            ConnectionState::Backoff => "backoff",
+        }
+    }
+}
+
+/// The management interface's side of a receiver
+#[derive(Clone)]
+pub struct ReceiverHandle {
+    pub account: String,
+    pub state: watch::Receiver<ConnectionState>,
+    catch_up: UnboundedSender<()>,
+}
+
+impl ReceiverHandle {
+    /// Asks the receiver to check for missed messages, or to reconnect right away if it's waiting to
+    pub fn catch_up(&self) -> bool {
+        self.catch_up.send(()).is_ok()
+    }
+}
+
+/// The receiver's side of its handle
+pub struct ReceiverControl {
+    state: watch::Sender<ConnectionState>,
+    catch_up: UnboundedReceiver<()>,
+}
+
+pub fn control_channel(account: &str) -> (ReceiverHandle, ReceiverControl) {
+    let (state_tx, state_rx) = watch::channel(ConnectionState::Disconnected);
+    let (catch_up_tx, catch_up_rx) = unbounded_channel();
+    let handle = ReceiverHandle { account: account.to_owned(), state: state_rx, catch_up: catch_up_tx };
+    (handle, ReceiverControl { state: state_tx, catch_up: catch_up_rx })
+}
+
+struct Backoff<'a> {
+    config: &'a ReceiverConfig,
+    attempt: i32,
+}
+
+impl<'a> Backoff<'a> {
+    fn new(config: &'a ReceiverConfig) -> Self {
+        Backoff { config, attempt: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.config.backoff_initial.as_secs_f64() * self.config.backoff_multiplier.powi(self.attempt);
+        let delay = delay.min(self.config.backoff_max.as_secs_f64());
+        let jitter = self.config.backoff_jitter;
+        let factor = if jitter > 0.0 { 1.0 + rand::thread_rng().gen_range(-jitter..=jitter) } else { 1.0 };
+        self.attempt = self.attempt.saturating_add(1);
+        Duration::from_secs_f64(delay * factor)
+    }
+}
+
+fn set_state(control: &ReceiverControl, new_state: ConnectionState) {
+    let state = *control.state.borrow();
+    if state != new_state {
+        debug!("Connection state: {:?} -> {:?}", state, new_state);
+        let _ = control.state.send(new_state);
+    }
+}
+
+/// The URL of Gotify's message stream, derived from the base URL in the login file
+pub fn websocket_url(login_file: &LoginFile) -> Result<Url, Error> {
+    let base_url = Url::parse(&login_file.gotify_base_url)
+        .map_err(|e| Error::Config(format!("Gotify base URL {} is not a valid URL: {}", login_file.gotify_base_url, e)))?;
+    let mut ws_url = base_url.clone();
+    let scheme = match base_url.scheme() {
+        "https" => "wss",
+        "http" => "ws",
+        scheme => return Err(Error::Config(format!("Only HTTPS and HTTP URLs are supported, not {}", scheme))),
+    };
+    ws_url.set_scheme(scheme)
+        .map_err(|_| Error::Config(format!("Can't use {} as a websocket URL", base_url)))?;
+    ws_url.path_segments_mut()
+        .map_err(|_| Error::Config(format!("Can't use {} as a websocket URL", base_url)))?
+        .push("stream");
+    ws_url.query_pairs_mut().append_pair("token", &login_file.gotify_device_token);
+    Ok(ws_url)
+}
+
+#[derive(Debug, PartialEq)]
+enum Reconnect {
+    AfterBackoff,
+    /// The network changed or the system woke up, so the old connection is likely stale
+    Immediately,
+    /// The system is offline or suspending, so there's no point in trying before it's back
+    WhenOnline,
+    Shutdown,
+}
+
+impl Reconnect {
+    fn after(event: SystemEvent) -> Self {
+        match event {
+            SystemEvent::Suspending | SystemEvent::NetworkDown => Reconnect::WhenOnline,
+            SystemEvent::Resumed | SystemEvent::NetworkUp | SystemEvent::NetworkChanged => Reconnect::Immediately,
+        }
+    }
+}
+
+/// Returns false if shutdown was requested while waiting
+async fn wait_until_online(system_events: &mut UnboundedReceiver<SystemEvent>, shutdown: &mut watch::Receiver<bool>) -> bool {
+    info!("Waiting for the system to come back online");
+    loop {
+        tokio::select! {
+            event = system_events.recv() => match event {
+                Some(event) if Reconnect::after(event) == Reconnect::Immediately => {
+                    info!("{:?}, reconnecting to Gotify", event);
+                    return true;
                 }
+                Some(_) => {}
+                None => return true,
+            },
+            _ = shutdown::requested(shutdown) => return false,
+        }
+    }
+}
+
+/// Sends a close frame and waits a moment for Gotify to answer it. Messages arriving meanwhile
+/// stay on the server and are caught up on the next start.
+async fn close_websocket<W, R>(ws_write: &mut W, ws_read: &mut R, timeout: Duration)
+where
+    W: futures_util::sink::Sink<WsMessage> + Unpin,
+    W::Error: std::fmt::Display,
+    R: futures_util::stream::Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let frame = CloseFrame { code: CloseCode::Normal, reason: "Shutting down".into() };
+    if let Err(e) = ws_write.send(WsMessage::Close(Some(frame))).await {
+        warn!("Failed to send websocket close frame: {}", e);
+        return;
+    }
+    let answered = tokio::time::timeout(timeout, async {
+        while let Some(Ok(message)) = ws_read.next().await {
+            if let WsMessage::Close(_) = message {
+                return true;
             }
         }
+        false
+    }).await.unwrap_or(false);
+    if !answered {
+        debug!("Gotify didn't answer the close frame");
     }
 }
 
 pub async fn run(
     sqlite_pool: Arc<r2d2::Pool<SqliteConnectionManager>>,
     dbus_connection: &'static zbus::azync::Connection,
-    login_file: LoginFile) {
-    
-    let base_url = Url::parse(&login_file.gotify_base_url).expect("Gotify base URL not a valid URL");
-    let websocket_url = {
-        let mut ws_url = base_url.clone();
-        if base_url.scheme() == "https" { ws_url.set_scheme("wss").unwrap(); }
-        else if base_url.scheme() == "http" { ws_url.set_scheme("ws").unwrap(); }
-        else { panic!("Only HTTPS and HTTP URLs supported"); }
-        ws_url.path_segments_mut().unwrap().push("stream");
-        ws_url.query_pairs_mut().append_pair("token", &login_file.gotify_device_token);
-        ws_url
-    };
+    login_file: LoginFile,
+    websocket_url: Url,
+    config: ReceiverConfig,
+    mut control: ReceiverControl,
+    mut system_events: UnboundedReceiver<SystemEvent>,
+    mut shutdown: watch::Receiver<bool>) {
 
-    debug!("Gotify websocket URL: {}", websocket_url);
+    debug!("Gotify websocket URL of account {}: {}", config.account, websocket_url);
 
+    let gotify = GotifyClient::from_login(&login_file);
     let sqlite_pool_ = sqlite_pool.clone();
-    tokio::spawn(check_removed_apps(sqlite_pool_, dbus_connection, login_file.clone()));
+    let removed_apps = tokio::spawn(check_removed_apps(sqlite_pool_, dbus_connection, gotify.clone(), config.clone(), shutdown.clone()));
+    let sqlite_pool_ = sqlite_pool.clone();
+    let retries = tokio::spawn(retry_pending_messages(sqlite_pool_, dbus_connection, gotify.clone(), config.clone(), shutdown.clone()));
 
+    let mut backoff = Backoff::new(&config);
     loop {
-        check_for_missed_messages(&sqlite_pool, dbus_connection, login_file.clone()).await;
+        set_state(&control, ConnectionState::Connecting);
+        let connection = tokio::select! {
+            connection = tokio_tungstenite::connect_async(&websocket_url) => connection,
+            _ = shutdown::requested(&mut shutdown) => break,
+        };
+        let next = match connection {
+            Ok((ws_stream, _)) => {
+                info!("Connected to Gotify account {}", config.account);
+                let connected_at = Instant::now();
+                let (mut ws_write, mut ws_read) = ws_stream.split();
 
-        if let Ok((mut ws_stream, _)) = tokio_tungstenite::connect_async(&websocket_url).await {
-            info!("Connected to Gotify");
-            
-            loop {
-                let message = tokio::time::timeout(std::time::Duration::from_secs(50), ws_stream.next()).await;
-                if let Ok(message) = message {
-                    if let Some(Ok(tokio_tungstenite::tungstenite::Message::Text(text))) = message {
-                        if let Ok(message) = serde_json::from_str::<GotifyMessage>(&text) {
-                            let id = message.id;
-                            if handle_message(&sqlite_pool, dbus_connection, message).await {
-                                delete_message(id, &login_file).await;
+                // Messages arriving during catch-up wait in the socket, so nothing falls in between
+                set_state(&control, ConnectionState::CatchingUp);
+                // Connecting catches up anyway
+                while let Some(Some(())) = control.catch_up.recv().now_or_never() {}
+                check_for_missed_messages(&sqlite_pool, dbus_connection, &gotify, &config).await;
+                set_state(&control, ConnectionState::Live);
+
+                let mut ping = tokio::time::interval(config.ping_interval);
+                let mut last_received = Instant::now();
+                let next = loop {
+                    tokio::select! {
+                        message = ws_read.next() => {
+                            match message {
+                                Some(Ok(WsMessage::Text(text))) => {
+                                    if let Ok(message) = serde_json::from_str::<GotifyMessage>(&text) {
+                                        handle_message(&sqlite_pool, dbus_connection, &gotify, &config, message).await;
+                                    }
+                                }
+                                Some(Ok(WsMessage::Close(frame))) => {
+                                    info!("Gotify closed the connection: {:?}", frame);
+                                    break Reconnect::AfterBackoff;
+                                }
+                                Some(Ok(_)) => {}
+                                Some(Err(e)) => {
+                                    warn!("Websocket error: {}", e);
+                                    break Reconnect::AfterBackoff;
+                                }
+                                None => {
+                                    warn!("Websocket closed");
+                                    break Reconnect::AfterBackoff;
+                                }
                             }
+                            last_received = Instant::now();
                         }
+                        _ = ping.tick() => {
+                            if last_received.elapsed() > config.ping_interval + config.pong_timeout {
+                                warn!("No pong from Gotify in time, reconnecting");
+                                break Reconnect::AfterBackoff;
+                            }
+                            if let Err(e) = ws_write.send(WsMessage::Ping(Vec::new())).await {
+                                warn!("Failed to send websocket ping: {}", e);
+                                break Reconnect::AfterBackoff;
+                            }
+                        }
+                        Some(()) = control.catch_up.recv() => {
+                            set_state(&control, ConnectionState::CatchingUp);
+                            check_for_missed_messages(&sqlite_pool, dbus_connection, &gotify, &config).await;
+                            set_state(&control, ConnectionState::Live);
+                        }
+                        Some(event) = system_events.recv() => {
+                            info!("{:?}, dropping the Gotify connection", event);
+                            break Reconnect::after(event);
+                        }
+                        _ = shutdown::requested(&mut shutdown) => break Reconnect::Shutdown,
+                    }
+                };
+                if next == Reconnect::Shutdown {
+                    close_websocket(&mut ws_write, &mut ws_read, config.close_timeout).await;
+                }
+                if let Err(e) = ws_write.close().await {
+                    debug!("Failed to close the websocket: {}", e);
+                }
+                set_state(&control, ConnectionState::Disconnected);
+                if connected_at.elapsed() >= STABLE_CONNECTION {
+                    backoff.reset();
+                }
+                next
+            }
+            Err(e) => {
+                info!("Failed to connect to Gotify account {}: {}", config.account, e);
+                Reconnect::AfterBackoff
+            }
+        };
+
+        match next {
+            Reconnect::AfterBackoff => {
+                let delay = backoff.next_delay();
+                info!("Reconnecting to Gotify in {:.1} seconds", delay.as_secs_f64());
+                set_state(&control, ConnectionState::Backoff);
+                let online = tokio::select! {
+                    _ = tokio::time::sleep(delay) => true,
+                    Some(()) = control.catch_up.recv() => {
+                        info!("Catch-up requested, reconnecting to Gotify now");
+                        backoff.reset();
+                        true
+                    }
+                    Some(event) = system_events.recv() => {
+                        backoff.reset();
+                        Reconnect::after(event) != Reconnect::WhenOnline
+                            || wait_until_online(&mut system_events, &mut shutdown).await
                     }
-                } else {
-                    warn!("Websocket error: {:?}", message);
+                    _ = shutdown::requested(&mut shutdown) => false,
+                };
+                if !online {
                     break;
                 }
             }
-            info!("Timed out or connection error!");
-            ws_stream.close(None).await;
-        } else {
-            info!("Failed to connect to Gotify! Retrying in 10 seconds");
-            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            Reconnect::Immediately => backoff.reset(),
+            Reconnect::WhenOnline => {
+                if !wait_until_online(&mut system_events, &mut shutdown).await {
+                    break;
+                }
+                backoff.reset();
+            }
+            Reconnect::Shutdown => break,
         }
     }
-}
\ No newline at end of file
+
+    // Let running flushes and the removal check finish their deliveries and database writes
+    let (removed_apps, retries) = futures_util::future::join(removed_apps, retries).await;
+    if let Err(e) = removed_apps {
+        error!("Removed app check failed: {}", e);
+    }
+    if let Err(e) = retries {
+        error!("Delivery retries failed: {}", e);
+    }
+    wait_for_flushes(&config.account).await;
+    info!("Gotify receiver of account {} stopped", config.account);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_reply(name: &str) -> Result<zbus::Result<zbus::Message>, tokio::time::error::Elapsed> {
+        let message = zbus::Message::method(None, Some("org.example.App"), "/", None, "Message", &()).unwrap();
+        Ok(Err(zbus::Error::MethodError(name.to_string(), None, message)))
+    }
+
+    #[test]
+    fn classifies_replies() {
+        let message = zbus::Message::method(None, Some("org.example.App"), "/", None, "Message", &()).unwrap();
+        assert_eq!(Delivery::from_reply(Ok(Ok(message))), Delivery::Delivered);
+        assert_eq!(Delivery::from_reply(error_reply("org.freedesktop.DBus.Error.ServiceUnknown")), Delivery::NoSuchApp);
+        assert_eq!(Delivery::from_reply(error_reply("org.freedesktop.DBus.Error.NameHasNoOwner")), Delivery::NoSuchApp);
+        assert_eq!(Delivery::from_reply(error_reply("org.freedesktop.DBus.Error.NoReply")), Delivery::Timeout);
+        assert_eq!(Delivery::from_reply(error_reply("org.freedesktop.DBus.Error.Timeout")), Delivery::Timeout);
+        assert_eq!(Delivery::from_reply(error_reply("org.example.App.Error.Full")), Delivery::AppError("org.example.App.Error.Full".to_string()));
+        assert_eq!(Delivery::from_reply(Ok(Err(zbus::Error::InvalidReply))), Delivery::Timeout);
+    }
+
+    #[tokio::test]
+    async fn treats_elapsed_calls_as_timeouts() {
+        let elapsed = tokio::time::timeout(Duration::from_millis(1), futures_util::future::pending::<zbus::Result<zbus::Message>>()).await;
+        assert_eq!(Delivery::from_reply(elapsed), Delivery::Timeout);
+    }
+
+    #[test]
+    fn detects_dead_registrations() {
+        for name in &["UnknownObject", "UnknownInterface", "UnknownMethod"] {
+            let delivery = Delivery::AppError(format!("org.freedesktop.DBus.Error.{}", name));
+            assert!(delivery.is_dead_registration(), "{}", name);
+        }
+        assert!(!Delivery::AppError("org.example.App.Error.Full".to_string()).is_dead_registration());
+        assert!(!Delivery::NoSuchApp.is_dead_registration());
+        assert!(!Delivery::Timeout.is_dead_registration());
+        assert!(!Delivery::Delivered.is_dead_registration());
+    }
+
+    #[test]
+    fn unregisters_apps_by_how_long_they_have_been_unreachable() {
+        let day = Duration::from_secs(86400);
+        assert!(!Delivery::NoSuchApp.is_dead_app(None, 100_000, day));
+        assert!(!Delivery::NoSuchApp.is_dead_app(Some(20_000), 100_000, day));
+        assert!(Delivery::NoSuchApp.is_dead_app(Some(10_000), 100_000, day));
+        assert!(Delivery::NoSuchApp.is_dead_app(Some(0), 100_000, day));
+        // Only an app that keeps being missing counts, however long ago it first was
+        assert!(!Delivery::Timeout.is_dead_app(Some(0), 100_000, day));
+        assert!(Delivery::AppError("org.freedesktop.DBus.Error.UnknownMethod".to_string()).is_dead_app(None, 100_000, day));
+    }
+
+    fn backoff_config(jitter: f64) -> ReceiverConfig {
+        let mut config = Config::default();
+        config.backoff.initial = 1.0;
+        config.backoff.max = 5.0;
+        config.backoff.multiplier = 2.0;
+        config.backoff.jitter = jitter;
+        ReceiverConfig::from_config(&config, "default")
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let config = backoff_config(0.0);
+        let mut backoff = Backoff::new(&config);
+        let delays: Vec<f64> = (0..6).map(|_| backoff.next_delay().as_secs_f64()).collect();
+        assert_eq!(delays, vec![1.0, 2.0, 4.0, 5.0, 5.0, 5.0]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn varies_delays_by_the_jitter() {
+        let config = backoff_config(0.2);
+        let mut backoff = Backoff::new(&config);
+        for expected in &[1.0, 2.0, 4.0, 5.0, 5.0] {
+            let delay = backoff.next_delay().as_secs_f64();
+            assert!(delay >= expected * 0.8 && delay <= expected * 1.2, "{} is too far from {}", delay, expected);
+        }
+    }
+
+    #[test]
+    fn publishes_state_changes_once() {
+        let (mut handle, control) = control_channel("default");
+        assert_eq!(*handle.state.borrow(), ConnectionState::Disconnected);
+
+        let transitions = [
+            ConnectionState::Connecting,
+            ConnectionState::CatchingUp,
+            ConnectionState::Live,
+            ConnectionState::Disconnected,
+            ConnectionState::Backoff,
+        ];
+        for state in &transitions {
+            set_state(&control, *state);
+            assert!(handle.state.changed().now_or_never().is_some(), "{:?} wasn't published", state);
+            assert_eq!(*handle.state.borrow(), *state);
+        }
+
+        // Staying in a state doesn't wake up the observers
+        set_state(&control, ConnectionState::Backoff);
+        assert!(handle.state.changed().now_or_never().is_none());
+    }
+
+    #[test]
+    fn names_states_for_the_management_interface() {
+        assert_eq!(ConnectionState::Disconnected.as_str(), "disconnected");
+        assert_eq!(ConnectionState::Connecting.as_str(), "connecting");
+        assert_eq!(ConnectionState::CatchingUp.as_str(), "catching-up");
+        assert_eq!(ConnectionState::Live.as_str(), "live");
+        assert_eq!(ConnectionState::Backoff.as_str(), "backoff");
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt::Write;
+
+use crate::registration::Reply;
+
+/// Arguments are `(name, signature)`, the name may be empty
+pub(crate) type Arg = (&'static str, &'static str);
+
+pub(crate) struct Method {
+    pub name: &'static str,
+    pub inputs: &'static [Arg],
+    pub outputs: &'static [Arg],
+    /// Accepted, but left out of the introspection data, which can't describe overloaded methods
+    pub hidden: bool,
+}
+
+pub(crate) struct Signal {
+    pub name: &'static str,
+    pub args: &'static [Arg],
+}
+
+/// The methods and signals of an interface, which calls are checked against and the introspection data is generated from
+pub(crate) struct Interface {
+    pub name: &'static str,
+    pub methods: &'static [Method],
+    pub signals: &'static [Signal],
+}
+
+pub(crate) const INTROSPECTABLE: Interface = Interface {
+    name: "org.freedesktop.DBus.Introspectable",
+    methods: &[Method { name: "Introspect", inputs: &[], outputs: &[("", "s")], hidden: false }],
+    signals: &[],
+};
+
+pub(crate) const PEER: Interface = Interface {
+    name: "org.freedesktop.DBus.Peer",
+    methods: &[Method { name: "Ping", inputs: &[], outputs: &[], hidden: false }],
+    signals: &[],
+};
+
+impl Method {
+    fn signature(&self) -> String {
+        self.inputs.iter().map(|(_, signature)| *signature).collect()
+    }
+}
+
+/// Refuses calls of methods the interfaces don't have, with the error the D-Bus specification asks for
+pub(crate) fn check_call(interfaces: &[&Interface], interface: &str, member: &str, signature: &str) -> Result<(), Reply> {
+    let found = match interfaces.iter().find(|i| i.name == interface) {
+        Some(found) => found,
+        None => return Err(Reply::Error(
+            "org.freedesktop.DBus.Error.UnknownInterface",
+            format!("Unknown interface {}", interface))),
+    };
+    if found.methods.iter().any(|m| m.name == member && m.signature() == signature) {
+        Ok(())
+    } else {
+        Err(unknown_method(interface, member, signature))
+    }
+}
+
+pub(crate) fn unknown_method(interface: &str, member: &str, signature: &str) -> Reply {
+    Reply::Error(
+        "org.freedesktop.DBus.Error.UnknownMethod",
+        format!("Unknown method {}.{} with signature \"{}\"", interface, member, signature))
+}
+
+/// The names of the nodes directly below `path` that lead to one of the objects
+pub(crate) fn children<'a>(path: &str, object_paths: &[&'a str]) -> Vec<&'a str> {
+    let prefix = if path == "/" { "/".to_owned() } else { format!("{}/", path) };
+    let mut children = Vec::new();
+    for &object_path in object_paths {
+        if let Some(rest) = object_path.strip_prefix(prefix.as_str()) {
+            let child = rest.split('/').next().unwrap_or(rest);
+            if !child.is_empty() && !children.contains(&child) {
+                children.push(child);
+            }
+        }
+    }
+    children
+}
+
+fn write_args(xml: &mut String, args: &[Arg], direction: Option<&str>) {
+    for (name, signature) in args {
+        xml.push_str("      <arg");
+        if !name.is_empty() {
+            let _ = write!(xml, " name=\"{}\"", name);
+        }
+        let _ = write!(xml, " type=\"{}\"", signature);
+        if let Some(direction) = direction {
+            let _ = write!(xml, " direction=\"{}\"", direction);
+        }
+        xml.push_str("/>\n");
+    }
+}
+
+/// The introspection data of the object at `path`, listing its interfaces and the nodes below it
+pub(crate) fn xml(path: &str, interfaces: &[&Interface], object_paths: &[&str]) -> String {
+    let mut xml = String::from(concat!(
+        "<!DOCTYPE node PUBLIC \"-//freedesktop//DTD D-BUS Object Introspection 1.0//EN\"\n",
+        " \"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd\">\n",
+        "<node>\n"));
+    for interface in interfaces {
+        let _ = writeln!(xml, "  <interface name=\"{}\">", interface.name);
+        for method in interface.methods.iter().filter(|m| !m.hidden) {
+            if method.inputs.is_empty() && method.outputs.is_empty() {
+                let _ = writeln!(xml, "    <method name=\"{}\"/>", method.name);
+                continue;
+            }
+            let _ = writeln!(xml, "    <method name=\"{}\">", method.name);
+            write_args(&mut xml, method.inputs, Some("in"));
+            write_args(&mut xml, method.outputs, Some("out"));
+            xml.push_str("    </method>\n");
+        }
+        for signal in interface.signals {
+            let _ = writeln!(xml, "    <signal name=\"{}\">", signal.name);
+            write_args(&mut xml, signal.args, None);
+            xml.push_str("    </signal>\n");
+        }
+        xml.push_str("  </interface>\n");
+    }
+    for child in children(path, object_paths) {
+        let _ = writeln!(xml, "  <node name=\"{}\"/>", child);
+    }
+    xml.push_str("</node>\n");
+    xml
+}
@@ -1,12 +1,60 @@
+use std::collections::HashMap;
+
 use serde::{ Serialize, Deserialize };
-use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
-use r2d2_sqlite::rusqlite::params;
+use r2d2_sqlite::rusqlite::{params, ToSql};
+
+pub mod config;
+pub mod error;
+pub mod files;
+pub mod gotify;
+pub mod migrations;
+pub mod secrets;
 
+pub use error::Error;
+
+/// Credentials of one Gotify account
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoginFile {
     pub gotify_base_url: String,
-    pub gotify_device_token: String
+    /// Empty if the token is kept in the Secret Service
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub gotify_device_token: String,
+    /// Whether `gotify_login` created the client, rather than being given the token of an existing one.
+    /// Only created clients are deleted on logout. Older versions always created one.
+    #[serde(default = "created_client_default")]
+    pub created_client: bool
+}
+
+fn created_client_default() -> bool {
+    true
+}
+
+/// Name of the account in a `login.json` written by older versions, and of their registrations
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    #[serde(flatten)]
+    pub login: LoginFile,
+}
+
+/// Contents of `login.json`, either a list of named accounts or a single unnamed one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum LoginsFile {
+    Accounts { accounts: Vec<Account> },
+    Single(LoginFile),
+}
+
+impl LoginsFile {
+    pub fn into_accounts(self) -> Vec<Account> {
+        match self {
+            LoginsFile::Accounts { accounts } => accounts,
+            LoginsFile::Single(login) => vec![Account { name: DEFAULT_ACCOUNT.to_owned(), login }],
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -16,46 +64,163 @@ pub struct GotifyApplication {
     pub token: String
 }
 
+/// Whether the name is a valid well-known bus name
+pub fn is_valid_bus_name(name: &str) -> bool {
+    name.len() <= 255
+        && name.contains('.')
+        && name.split('.').all(|element| {
+            !element.is_empty()
+                && !element.starts_with(|c: char| c.is_ascii_digit())
+                && element.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+/// Object path of the daemon's management interface, `org.unifiedpush.Gotify.Manager1`
+pub const MANAGER_PATH: &str = "/org/unifiedpush/Gotify/Manager";
+pub const MANAGER_INTERFACE: &str = "org.unifiedpush.Gotify.Manager1";
+
+/// The app registered through `org.unifiedpush.Distributor1` and expects `org.unifiedpush.Connector1` calls
+pub const DBUS_API_V1: i32 = 1;
+/// The app registered through `org.unifiedpush.Distributor2` and expects `org.unifiedpush.Connector2` calls
+pub const DBUS_API_V2: i32 = 2;
+
 #[derive(Debug)]
 pub struct DbUpConnection {
+    /// The Gotify account the app's messages come from
+    pub account: String,
     pub appid: String,
     pub token: String,
     pub gotify_id: i32,
     pub gotify_token: String,
+    pub description: String,
+    pub dbus_version: i32,
+    pub vapid: String,
+    /// Unix time of the registration
+    pub registered_at: i64,
+    /// Newest message id on the server when the app registered, later messages are for the app
+    pub first_message_id: i32,
+}
+
+fn read_connection(row: &r2d2_sqlite::rusqlite::Row) -> r2d2_sqlite::rusqlite::Result<DbUpConnection> {
+    Ok(DbUpConnection {
+        account: row.get("account")?,
+        appid: row.get("appid")?,
+        token: row.get("token")?,
+        gotify_id: row.get("gotify_id")?,
+        gotify_token: row.get("gotify_token")?,
+        description: row.get("description")?,
+        dbus_version: row.get("dbus_version")?,
+        vapid: row.get("vapid")?,
+        registered_at: row.get("registered_at")?,
+        first_message_id: row.get("first_message_id")?
+    })
 }
 
-pub fn get_connections_with_token<'a>(pool: &'a r2d2::Pool<SqliteConnectionManager>, token: &str) -> Result<Vec<DbUpConnection>, Box<dyn std::error::Error + Send + Sync>> {
+/// Returns the registrations matching the `WHERE` clause, ordered by app
+fn query_connections(
+    pool: &r2d2::Pool<SqliteConnectionManager>,
+    condition: &str,
+    params: &[&dyn ToSql]) -> Result<Vec<DbUpConnection>, Error> {
     let conn = pool.get()?;
-    let mut s = conn.prepare("SELECT * FROM connections WHERE token=?")?;
-    let mut rows = s.query(&[token])?;
+    let mut s = conn.prepare(&format!("SELECT * FROM connections WHERE {} ORDER BY appid, token", condition))?;
+    let mut rows = s.query(params)?;
 
     let mut result = Vec::new();
-    while let Ok(Some(row)) = rows.next() {
-        result.push(DbUpConnection {
-            appid: row.get_unwrap("appid"),
-            token: row.get_unwrap("token"),
-            gotify_id: row.get_unwrap("gotify_id"),
-            gotify_token: row.get_unwrap("gotify_token")
-        });
+    while let Some(row) = rows.next()? {
+        result.push(read_connection(row)?);
     }
 
     Ok(result)
 }
 
-pub fn get_all_connections<'a>(pool: &'a r2d2::Pool<SqliteConnectionManager>) -> Result<Vec<DbUpConnection>, Box<dyn std::error::Error + Send + Sync>> {
+pub fn get_connections_with_token(pool: &r2d2::Pool<SqliteConnectionManager>, token: &str) -> Result<Vec<DbUpConnection>, Error> {
+    query_connections(pool, "token = ?", params![token])
+}
+
+pub fn get_all_connections(pool: &r2d2::Pool<SqliteConnectionManager>) -> Result<Vec<DbUpConnection>, Error> {
+    query_connections(pool, "1", params![])
+}
+
+/// Returns the registrations of one app, which has one per token it registered with
+pub fn get_app_connections(pool: &r2d2::Pool<SqliteConnectionManager>, appid: &str) -> Result<Vec<DbUpConnection>, Error> {
+    query_connections(pool, "appid = ?", params![appid])
+}
+
+/// Returns the registrations whose messages come from the given account
+pub fn get_account_connections(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str) -> Result<Vec<DbUpConnection>, Error> {
+    query_connections(pool, "account = ?", params![account])
+}
+
+/// Delivery states of messages in the `deliveries` table
+pub const DELIVERY_PENDING: &str = "pending";
+/// The connector app acknowledged the message, but it may still be on the server
+pub const DELIVERY_DELIVERED: &str = "delivered";
+/// The connector app replied with an error, so the message won't be retried
+pub const DELIVERY_REJECTED: &str = "rejected";
+
+/// A push message received from Gotify that hasn't been acknowledged by the connector app yet
+#[derive(Debug)]
+pub struct DbPendingMessage {
+    pub message_id: i32,
+    pub gotify_id: i32,
+    pub data: String,
+    pub attempts: i32,
+    /// Unix time a delivery first found the app neither running nor activatable, if it still was last time
+    pub unreachable_since: Option<i64>,
+    pub appid: String,
+    pub token: String,
+    pub dbus_version: i32,
+}
+
+pub fn get_pending_messages(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str) -> Result<Vec<DbPendingMessage>, Error> {
     let conn = pool.get()?;
-    let mut s = conn.prepare("SELECT * FROM connections")?;
-    let mut rows = s.query(params![])?;
+    let mut s = conn.prepare(
+        "SELECT deliveries.*, connections.appid, connections.token, connections.dbus_version
+        FROM deliveries JOIN connections
+            ON deliveries.account = connections.account AND deliveries.gotify_id = connections.gotify_id
+        WHERE deliveries.account = ? AND deliveries.state = ?
+        ORDER BY deliveries.message_id")?;
+    let mut rows = s.query(&[account, DELIVERY_PENDING])?;
 
     let mut result = Vec::new();
-    while let Ok(Some(row)) = rows.next() {
-        result.push(DbUpConnection {
-            appid: row.get_unwrap("appid"),
-            token: row.get_unwrap("token"),
-            gotify_id: row.get_unwrap("gotify_id"),
-            gotify_token: row.get_unwrap("gotify_token")
+    while let Some(row) = rows.next()? {
+        result.push(DbPendingMessage {
+            message_id: row.get("message_id")?,
+            gotify_id: row.get("gotify_id")?,
+            data: row.get("data")?,
+            attempts: row.get("attempts")?,
+            unreachable_since: row.get("unreachable_since")?,
+            appid: row.get("appid")?,
+            token: row.get("token")?,
+            dbus_version: row.get("dbus_version")?
         });
     }
 
     Ok(result)
-}
\ No newline at end of file
+}
+
+
+/// Returns the id up to which each Gotify application's messages have been queued, by application id
+pub fn get_app_watermarks(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str) -> Result<HashMap<i32, i32>, Error> {
+    let conn = pool.get()?;
+    let mut s = conn.prepare("SELECT gotify_id, message_id FROM app_watermarks WHERE account=?")?;
+    let mut rows = s.query(&[account])?;
+
+    let mut result = HashMap::new();
+    while let Some(row) = rows.next()? {
+        result.insert(row.get(0)?, row.get(1)?);
+    }
+
+    Ok(result)
+}
+
+/// Forgets the registrations, deliveries and watermarks of the account
+pub fn delete_account_data(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    for table in &["connections", "deliveries", "app_watermarks"] {
+        tx.execute(&format!("DELETE FROM {} WHERE account=?", table), &[account])?;
+    }
+    tx.commit()?;
+    Ok(())
+}
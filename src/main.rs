@@ -1,73 +1,187 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
-use std::error::Error as StdError;
 use std::sync::Arc;
 use std::fs::File;
-use std::path::PathBuf;
-use lazy_static::lazy_static;
-use directories_next::ProjectDirs;
+use std::path::{Path, PathBuf};
+use clap::Clap;
 use r2d2_sqlite::SqliteConnectionManager;
-use r2d2_sqlite::rusqlite::params;
-use log::{error, warn, info, debug, trace};
+use log::{error, info, debug};
 
 mod registration;
 mod gotify_receiver;
+mod system_events;
+mod shutdown;
+mod introspection;
+mod bus;
+mod manager;
 
-use unifiedpush_gotify_lib::LoginFile;
+use unifiedpush_gotify_lib::{Account, Error, LoginsFile, files, migrations};
+use unifiedpush_gotify_lib::secrets::SecretService;
+use unifiedpush_gotify_lib::config::{self, Bus, Overrides};
 
-#[derive(Debug)]
-enum Error {
-    CantReadConfigFile,
-    StdError(Box<dyn StdError>)
+/// Settings given here take precedence over the environment, which takes precedence over `config.toml`
+#[derive(Clap)]
+#[clap(version = "0.1", author = "vurpo")]
+struct Options {
+    /// Path of the configuration file, `config.toml` next to `login.json` by default
+    #[clap(long = "config", env = "UNIFIEDPUSH_GOTIFY_CONFIG")]
+    config: Option<PathBuf>,
+    /// "session" or "system"
+    #[clap(long = "bus")]
+    bus: Option<Bus>,
+    #[clap(long = "bus-name")]
+    bus_name: Option<String>,
+    #[clap(long = "database")]
+    database: Option<PathBuf>,
+    #[clap(long = "log-level")]
+    log_level: Option<String>,
+    /// Leave delivered messages on the Gotify server
+    #[clap(long = "keep-messages")]
+    keep_messages: bool,
 }
-impl<T: 'static> From<T> for Error where T: StdError {
-    fn from(error: T) -> Self {
-        Error::StdError(Box::new(error))
+
+fn config_source(opts: Options, config_dir: &Path) -> config::Source {
+    config::Source {
+        path: opts.config.unwrap_or_else(|| config_dir.join("config.toml")),
+        overrides: Overrides {
+            bus: opts.bus,
+            bus_name: opts.bus_name,
+            database: opts.database,
+            log_level: opts.log_level,
+            delete_after_delivery: if opts.keep_messages { Some(false) } else { None },
+        },
     }
 }
 
-lazy_static! {
-    static ref DBUS_CONNECTION: zbus::Connection = zbus::Connection::new_session().unwrap();
+/// Fills in the device tokens `gotify_login` stored in the Secret Service instead of `login.json`.
+/// The Secret Service is on the user's session bus, which a daemon on the system bus usually can't reach.
+fn load_secret_tokens(accounts: &mut [Account], bus: Bus) -> Result<(), Error> {
+    if accounts.iter().all(|a| !a.login.gotify_device_token.is_empty()) {
+        return Ok(());
+    }
+    if bus == Bus::System {
+        let missing: Vec<&str> = accounts.iter()
+            .filter(|a| a.login.gotify_device_token.is_empty())
+            .map(|a| a.name.as_str())
+            .collect();
+        return Err(Error::SecretService(format!(
+            "The keyring isn't available on the system bus, log in again with bus = \"system\" in config.toml to keep the device token of {} in login.json",
+            missing.join(", "))));
+    }
+    let secrets = SecretService::connect()?;
+    for account in accounts.iter_mut().filter(|a| a.login.gotify_device_token.is_empty()) {
+        account.login.gotify_device_token = secrets.load_token(&account.name, &account.login.gotify_base_url)?
+            .ok_or_else(|| Error::SecretService(format!("No device token for account {}, log in again", account.name)))?;
+        debug!("Loaded the device token of account {} from the Secret Service", account.name);
+    }
+    Ok(())
 }
 
-fn main() -> Result<(), Error> {
-    env_logger::init();
+fn main() {
+    let opts: Options = Options::parse();
+    // Logging isn't set up yet, so these errors go to stderr directly
+    let loaded = config::default_config_dir().and_then(|config_dir| {
+        let config_source = config_source(opts, &config_dir);
+        let config = config_source.load()?;
+        let log_level = config.log_level_filter()?;
+        Ok((config_dir, config_source, config, log_level))
+    });
+    let (config_dir, config_source, config, log_level) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // RUST_LOG can still refine the level per module
+    env_logger::Builder::new()
+        .filter_level(log_level)
+        .parse_env("RUST_LOG")
+        .init();
     info!("Starting UnifiedPush Gotify receiver");
+    debug!("Configuration: {:?}", config);
+
+    if let Err(e) = run(config_dir, config_source, config) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    info!("Shut down cleanly");
+}
+
+/// Runs the daemon until it's asked to shut down. Errors are left to the caller to report.
+fn run(config_dir: PathBuf, config_source: config::Source, config: config::Config) -> Result<(), Error> {
 
-    let project_dirs = ProjectDirs::from("fi", "vurpo", "UnifiedPushGotify")
-        .ok_or(Error::CantReadConfigFile)?;
-    let config_dir = project_dirs.config_dir();
     let login_file_path = {
-        let mut buf = PathBuf::from(config_dir);
+        let mut buf = config_dir.clone();
         buf.push("login.json");
         buf
     };
-    let login_file: LoginFile = serde_json::from_reader(&File::open(&login_file_path)?)?;
-    debug!("Login file loaded OK");
+    let db_path = config.database_path(&config_dir);
+    for path in &[&config_dir, &login_file_path, &config_source.path, &db_path] {
+        files::check_permissions(path)?;
+    }
 
-    let db_path = {
-        let mut buf = PathBuf::from(config_dir);
-        buf.push("database.db");
-        buf
-    };
+    let logins: LoginsFile = File::open(&login_file_path)
+        .map_err(Error::from)
+        .and_then(|file| serde_json::from_reader(file).map_err(Error::from))
+        .map_err(|e| Error::Config(format!("Can't read {}, log in again with gotify_login: {}", login_file_path.display(), e)))?;
+    let mut accounts = logins.into_accounts();
+    load_secret_tokens(&mut accounts, config.bus)?;
+    config.validate_accounts(&accounts)?;
+    let websocket_urls = accounts.iter()
+        .map(|account| gotify_receiver::websocket_url(&account.login))
+        .collect::<Result<Vec<_>, _>>()?;
+    debug!("Login file loaded OK, {} account(s)", accounts.len());
+
+    files::create_private_file(&db_path)?;
     let sqlite_connection_manager = SqliteConnectionManager::file(&db_path);
     let sqlite_pool = Arc::new(r2d2::Pool::new(sqlite_connection_manager)?);
     debug!("Connection database loaded OK");
 
-    sqlite_pool.get()?
-        .execute("CREATE TABLE IF NOT EXISTS connections (appid TEXT NOT NULL, token TEXT NOT NULL, gotify_token TEXT NOT NULL, gotify_id INTEGER NOT NULL)", params![])?;
+    migrations::migrate(&mut *sqlite_pool.get()?)?;
+    debug!("Connection database schema up to date");
+
+    // Shared by every task for the lifetime of the process
+    let dbus_connection = match config.bus {
+        Bus::Session => zbus::Connection::new_session()?,
+        Bus::System => zbus::Connection::new_system()?,
+    };
+    let dbus_connection: &'static zbus::Connection = Box::leak(Box::new(dbus_connection));
 
-    sqlite_pool.get()?
-        .execute("CREATE TABLE IF NOT EXISTS last_seen_message (message_id INTEGER NOT NULL)", params![])?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let shutdown_tx = Arc::new(shutdown_tx);
+    runtime.spawn(shutdown::on_signal(shutdown_tx.clone()));
+    let mut system_events_txs = Vec::new();
+    let mut receivers = Vec::new();
+    let mut receiver_handles = Vec::new();
+    for (account, websocket_url) in accounts.iter().zip(websocket_urls) {
+        debug!("Starting Gotify receiver of account {}", account.name);
+        let (system_events_tx, system_events_rx) = tokio::sync::mpsc::unbounded_channel();
+        system_events_txs.push(system_events_tx);
+        let receiver_config = gotify_receiver::ReceiverConfig::from_config(&config, &account.name);
+        let (handle, control) = gotify_receiver::control_channel(&account.name);
+        receiver_handles.push(handle);
+        receivers.push(runtime.spawn(gotify_receiver::run(sqlite_pool.clone(), dbus_connection.inner(), account.login.clone(), websocket_url, receiver_config, control, system_events_rx, shutdown_rx.clone())));
+    }
+    runtime.spawn(system_events::run(system_events_txs));
 
-    let sqlite_pool_ = sqlite_pool.clone();
-    let login_file_ = login_file.clone();
-    std::thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        debug!("Starting Gotify receiver thread");
-        rt.block_on(gotify_receiver::run(sqlite_pool_, &*DBUS_CONNECTION.inner(), login_file_));
-    });
+    debug!("Starting D-Bus registration receiver");
+    let result = runtime.block_on(registration::run(sqlite_pool, dbus_connection.inner(), accounts, config.clone(), config_source, receiver_handles, shutdown_rx));
 
-    debug!("Starting D-Bus registration receiver thread");
-    registration::run(sqlite_pool, &*DBUS_CONNECTION, login_file).map_err(|e| Error::StdError(e))
+    // The registration receiver also stops on errors, and the Gotify receivers have to stop with it
+    let _ = shutdown_tx.send(true);
+    let shutdown_timeout = config::seconds(config.timeouts.shutdown);
+    let stopped = runtime.block_on(async { tokio::time::timeout(shutdown_timeout, futures_util::future::join_all(receivers)).await });
+    if let Ok(results) = &stopped {
+        for e in results.iter().filter_map(|r| r.as_ref().err()) {
+            error!("Gotify receiver failed: {}", e);
+        }
+    }
+    result?;
+    if stopped.is_err() {
+        return Err(Error::ShutdownTimedOut);
+    }
+    Ok(())
 }
\ No newline at end of file
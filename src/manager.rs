@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use r2d2_sqlite::SqliteConnectionManager;
+use r2d2_sqlite::rusqlite::params;
+use unifiedpush_gotify_lib::{
+    Error,
+    DbUpConnection,
+    DELIVERY_PENDING,
+    DELIVERY_DELIVERED,
+    MANAGER_INTERFACE,
+    MANAGER_PATH,
+    get_all_connections,
+    get_app_connections,
+    get_connections_with_token,
+};
+use zvariant::Value;
+use log::{error, warn, info, debug, trace};
+
+use crate::bus;
+use crate::gotify_receiver::ReceiverHandle;
+use crate::introspection::{self, Interface, Method, Signal, INTROSPECTABLE};
+use crate::registration::{Distributor, Reply, OBJECT_PATHS, invalid_args};
+
+const MANAGER1: Interface = Interface {
+    name: MANAGER_INTERFACE,
+    methods: &[
+        Method { name: "ListRegistrations", inputs: &[], outputs: &[("", "aa{sv}")], hidden: false },
+        Method { name: "GetApp", inputs: &[("appid", "s")], outputs: &[("", "aa{sv}")], hidden: false },
+        Method { name: "Unregister", inputs: &[("token", "s")], outputs: &[], hidden: false },
+        Method { name: "ResendEndpoint", inputs: &[("token", "s")], outputs: &[], hidden: false },
+        Method { name: "GetQueueStatus", inputs: &[], outputs: &[("", "aa{sv}")], hidden: false },
+        Method { name: "GetConnectionStates", inputs: &[], outputs: &[("", "a{ss}")], hidden: false },
+        Method { name: "CatchUp", inputs: &[], outputs: &[], hidden: false },
+        Method { name: "ReloadConfig", inputs: &[], outputs: &[("restart_required", "as")], hidden: false },
+    ],
+    signals: &[
+        Signal { name: "ConnectionStateChanged", args: &[("account", "s"), ("state", "s")] },
+        Signal { name: "MessageReceived", args: &[("account", "s"), ("gotify_id", "i"), ("message_id", "i")] },
+        Signal { name: "PushDelivered", args: &[("appid", "s"), ("account", "s"), ("message_id", "i")] },
+    ],
+};
+
+const INTERFACES: &[&Interface] = &[&MANAGER1, &INTROSPECTABLE];
+
+type Dict = HashMap<&'static str, Value<'static>>;
+
+fn registration_info(distributor: &Distributor, connection: &DbUpConnection) -> Dict {
+    let mut info = HashMap::new();
+    info.insert("appid", Value::from(connection.appid.clone()));
+    info.insert("token", Value::from(connection.token.clone()));
+    info.insert("account", Value::from(connection.account.clone()));
+    info.insert("gotify_id", Value::from(connection.gotify_id));
+    info.insert("description", Value::from(connection.description.clone()));
+    info.insert("dbus_version", Value::from(connection.dbus_version));
+    info.insert("registered_at", Value::from(connection.registered_at));
+    // Empty if the registration's account was removed from login.json
+    info.insert("endpoint", Value::from(distributor.endpoint_of(connection).unwrap_or_default()));
+    info
+}
+
+fn count_deliveries(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str, gotify_id: Option<i32>, state: &str) -> Result<i64, Error> {
+    let count = pool.get()?.query_row(
+        "SELECT COUNT(*) FROM deliveries WHERE account = ? AND (? IS NULL OR gotify_id = ?) AND state = ?",
+        params![account, gotify_id, gotify_id, state],
+        |r| r.get(0))?;
+    Ok(count)
+}
+
+/// The newest message id queued for the app, or for any app of the account without `gotify_id`
+fn last_seen_message(pool: &r2d2::Pool<SqliteConnectionManager>, account: &str, gotify_id: Option<i32>) -> Result<i32, Error> {
+    let last_seen: Option<i32> = pool.get()?.query_row(
+        "SELECT MAX(message_id) FROM app_watermarks WHERE account = ? AND (? IS NULL OR gotify_id = ?)",
+        params![account, gotify_id, gotify_id],
+        |r| r.get(0))?;
+    Ok(last_seen.unwrap_or(0))
+}
+
+fn list_registrations(distributor: &Distributor) -> Result<Vec<Dict>, Error> {
+    Ok(get_all_connections(&distributor.sqlite_pool)?.iter()
+        .map(|c| registration_info(distributor, c))
+        .collect())
+}
+
+fn get_app(distributor: &Distributor, appid: &str) -> Result<Vec<Dict>, Error> {
+    let pool = &distributor.sqlite_pool;
+    let mut result = Vec::new();
+    for connection in get_app_connections(pool, appid)? {
+        let mut info = registration_info(distributor, &connection);
+        // Lets gotify_ctl send test messages as the app's Gotify application
+        info.insert("gotify_token", Value::from(connection.gotify_token.clone()));
+        info.insert("gotify_url", Value::from(distributor.gotify_url(&connection.account).unwrap_or_default()));
+        info.insert("last_seen_message", Value::from(last_seen_message(pool, &connection.account, Some(connection.gotify_id))?));
+        info.insert("pending", Value::from(count_deliveries(pool, &connection.account, Some(connection.gotify_id), DELIVERY_PENDING)?));
+        result.push(info);
+    }
+    Ok(result)
+}
+
+fn queue_status(distributor: &Distributor) -> Result<Vec<Dict>, Error> {
+    let pool = &distributor.sqlite_pool;
+    let mut result = Vec::new();
+    for account in distributor.accounts.iter() {
+        let mut info: Dict = HashMap::new();
+        info.insert("account", Value::from(account.name.clone()));
+        info.insert("last_seen_message", Value::from(last_seen_message(pool, &account.name, None)?));
+        info.insert("pending", Value::from(count_deliveries(pool, &account.name, None, DELIVERY_PENDING)?));
+        // Acknowledged by the app, but not deleted from the server yet
+        info.insert("undeleted", Value::from(count_deliveries(pool, &account.name, None, DELIVERY_DELIVERED)?));
+        result.push(info);
+    }
+    Ok(result)
+}
+
+fn connections_with_token(distributor: &Distributor, token: &str) -> Result<Vec<DbUpConnection>, Reply> {
+    match get_connections_with_token(&distributor.sqlite_pool, token) {
+        Ok(list) if list.is_empty() => Err(invalid_args(format!("No app is registered with token {}", token))),
+        Ok(list) => Ok(list),
+        Err(e) => Err(database_error(e)),
+    }
+}
+
+fn database_error(e: Error) -> Reply {
+    error!("Management call failed: {}", e);
+    Reply::Error("org.freedesktop.DBus.Error.Failed", e.to_string())
+}
+
+fn list_reply(result: Result<Vec<Dict>, Error>) -> Reply {
+    match result {
+        Ok(list) => Reply::List(list),
+        Err(e) => database_error(e),
+    }
+}
+
+/// Reads the configuration again and applies the settings that can change while running
+fn reload_config(distributor: &Distributor) -> Reply {
+    let config = match distributor.config_source.load() {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("Not reloading the configuration: {}", e);
+            return Reply::Error("org.freedesktop.DBus.Error.Failed", e.to_string());
+        }
+    };
+    if let Err(e) = config.validate_accounts(&distributor.accounts) {
+        warn!("Not reloading the configuration: {}", e);
+        return Reply::Error("org.freedesktop.DBus.Error.Failed", e.to_string());
+    }
+    let restart_required = config.restart_required(&distributor.config());
+    if !restart_required.is_empty() {
+        warn!("Changes to {} take effect after a restart", restart_required.join(", "));
+    }
+    distributor.set_config(config);
+    info!("Configuration reloaded");
+    Reply::Strings(restart_required)
+}
+
+/// Emits `ConnectionStateChanged` on every state change of the receiver, until it stops
+pub(crate) async fn publish_state_changes(dbus_conn: &'static zbus::azync::Connection, mut receiver: ReceiverHandle) {
+    while receiver.state.changed().await.is_ok() {
+        let state = receiver.state.borrow().as_str();
+        let body = (receiver.account.as_str(), state);
+        if let Err(e) = dbus_conn.emit_signal(None, MANAGER_PATH, MANAGER_INTERFACE, "ConnectionStateChanged", &body).await {
+            warn!("Failed to emit ConnectionStateChanged: {}", e);
+        }
+    }
+}
+
+/// Only the user the daemon runs as and root may manage registrations, which matters on the system bus
+async fn is_authorized(distributor: &Distributor, message: &zbus::Message) -> bool {
+    let sender = match message.header().ok().and_then(|h| h.sender().ok().flatten().map(|s| s.to_string())) {
+        Some(sender) => sender,
+        None => return false,
+    };
+    match bus::connection_unix_user(distributor.dbus_conn, &sender).await {
+        Ok(uid) => uid == 0 || Some(uid) == distributor.uid,
+        Err(e) => {
+            warn!("Couldn't look up the user of {}: {}", sender, e);
+            false
+        }
+    }
+}
+
+/// Handles calls to `org.unifiedpush.Gotify.Manager1`, which `gotify_ctl` uses
+pub(crate) async fn handle_call(distributor: &Distributor, message: &zbus::Message) -> Reply {
+    let header = match message.header() {
+        Ok(header) => header,
+        Err(e) => return invalid_args(e),
+    };
+    let interface = header.interface().ok().flatten().unwrap_or("");
+    let member = header.member().ok().flatten().unwrap_or("");
+    let signature = message.body_signature().map(|s| s.as_str().to_owned()).unwrap_or_default();
+    if let Err(reply) = introspection::check_call(INTERFACES, interface, member, &signature) {
+        return reply;
+    }
+    if interface == INTROSPECTABLE.name {
+        return Reply::Str(introspection::xml(MANAGER_PATH, INTERFACES, OBJECT_PATHS));
+    }
+    if !is_authorized(distributor, message).await {
+        return Reply::Error("org.freedesktop.DBus.Error.AccessDenied", "Not allowed to manage the distributor".to_owned());
+    }
+    debug!("Management call {}", member);
+    match member {
+        "ListRegistrations" => list_reply(list_registrations(distributor)),
+        "GetApp" => match message.body::<&str>() {
+            Ok(appid) => list_reply(get_app(distributor, appid)),
+            Err(e) => invalid_args(e),
+        },
+        "GetQueueStatus" => list_reply(queue_status(distributor)),
+        "GetConnectionStates" => Reply::States(distributor.receivers.iter()
+            .map(|r| (r.account.clone(), r.state.borrow().as_str()))
+            .collect()),
+        "CatchUp" => {
+            info!("Catching up on request of the management interface");
+            for receiver in distributor.receivers.iter() {
+                if !receiver.catch_up() {
+                    warn!("Gotify receiver of account {} isn't running", receiver.account);
+                }
+            }
+            Reply::Empty
+        }
+        "ReloadConfig" => reload_config(distributor),
+        "Unregister" => match message.body::<&str>() {
+            Ok(token) => match connections_with_token(distributor, token) {
+                Ok(list) => {
+                    for connection in &list {
+                        info!("Unregistering {} on request of the management interface", connection.appid);
+                    }
+                    distributor.unregister_app(token).await;
+                    Reply::Empty
+                }
+                Err(reply) => reply,
+            },
+            Err(e) => invalid_args(e),
+        },
+        "ResendEndpoint" => match message.body::<&str>() {
+            Ok(token) => match connections_with_token(distributor, token) {
+                Ok(list) => {
+                    for connection in &list {
+                        if let Err(e) = distributor.resend_endpoint(connection).await {
+                            error!("Failed to send new endpoint to {}: {}", connection.appid, e);
+                            return Reply::Error("org.freedesktop.DBus.Error.Failed", e.to_string());
+                        }
+                        trace!("Sent endpoint to {} again", connection.appid);
+                    }
+                    Reply::Empty
+                }
+                Err(reply) => reply,
+            },
+            Err(e) => invalid_args(e),
+        },
+        _ => introspection::unknown_method(interface, member, &signature),
+    }
+}
@@ -0,0 +1,343 @@
+use r2d2_sqlite::rusqlite::{params, Connection, Transaction};
+use log::{info, debug};
+
+use crate::Error;
+
+type Migration = fn(&Transaction) -> r2d2_sqlite::rusqlite::Result<()>;
+
+/// Migration `i` upgrades a database from `user_version` `i` to `i + 1`
+const MIGRATIONS: &[Migration] = &[
+    create_tables,
+    add_registration_columns,
+    add_constraints,
+    add_registration_watermark,
+    track_deliveries_per_app,
+    add_accounts,
+    add_unreachable_since,
+];
+
+/// The schema version of databases created by this version of the daemon
+pub const SCHEMA_VERSION: i32 = MIGRATIONS.len() as i32;
+
+/// Upgrades the database in place to the latest schema, one migration per transaction.
+pub fn migrate(conn: &mut Connection) -> Result<(), Error> {
+    let version: i32 = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(Error::UnsupportedSchema(version));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        debug!("Migrating database from schema version {} to {}", from, from + 1);
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", &(from as i32 + 1))?;
+        tx.commit()?;
+    }
+    if version < SCHEMA_VERSION {
+        info!("Database migrated from schema version {} to {}", version, SCHEMA_VERSION);
+    }
+    Ok(())
+}
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> r2d2_sqlite::rusqlite::Result<bool> {
+    let s = tx.prepare(&format!("SELECT * FROM {} LIMIT 0", table))?;
+    let found = s.column_names().contains(&column);
+    Ok(found)
+}
+
+/// The original layout. Databases from before versioning already have these tables.
+fn create_tables(tx: &Transaction) -> r2d2_sqlite::rusqlite::Result<()> {
+    tx.execute("CREATE TABLE IF NOT EXISTS connections (appid TEXT NOT NULL, token TEXT NOT NULL, gotify_token TEXT NOT NULL, gotify_id INTEGER NOT NULL)", params![])?;
+    tx.execute("CREATE TABLE IF NOT EXISTS last_seen_message (message_id INTEGER NOT NULL)", params![])?;
+    Ok(())
+}
+
+/// Registration details from the Distributor1/Distributor2 arguments, and the delivery queue.
+/// Unversioned databases may already have some of these from ad-hoc upgrades.
+fn add_registration_columns(tx: &Transaction) -> r2d2_sqlite::rusqlite::Result<()> {
+    for (column, definition) in &[
+        ("description", "TEXT NOT NULL DEFAULT ''"),
+        ("dbus_version", "INTEGER NOT NULL DEFAULT 1"),
+        ("vapid", "TEXT NOT NULL DEFAULT ''"),
+    ] {
+        if !has_column(tx, "connections", column)? {
+            tx.execute(&format!("ALTER TABLE connections ADD COLUMN {} {}", column, definition), params![])?;
+        }
+    }
+    tx.execute("CREATE TABLE IF NOT EXISTS pending_messages (message_id INTEGER PRIMARY KEY, gotify_id INTEGER NOT NULL, data TEXT NOT NULL, attempts INTEGER NOT NULL DEFAULT 0)", params![])?;
+    Ok(())
+}
+
+/// Rebuilds the tables with keys and indices. Duplicate registrations keep their oldest row,
+/// and `last_seen_message` becomes a single row holding the highest id seen.
+fn add_constraints(tx: &Transaction) -> r2d2_sqlite::rusqlite::Result<()> {
+    tx.execute_batch("
+        CREATE TABLE connections_new (
+            appid TEXT NOT NULL,
+            token TEXT NOT NULL,
+            gotify_token TEXT NOT NULL,
+            gotify_id INTEGER NOT NULL UNIQUE,
+            description TEXT NOT NULL DEFAULT '',
+            dbus_version INTEGER NOT NULL DEFAULT 1,
+            vapid TEXT NOT NULL DEFAULT '',
+            UNIQUE (appid, token)
+        );
+        INSERT OR IGNORE INTO connections_new (appid, token, gotify_token, gotify_id, description, dbus_version, vapid)
+            SELECT appid, token, gotify_token, gotify_id, description, dbus_version, vapid FROM connections ORDER BY rowid;
+        DROP TABLE connections;
+        ALTER TABLE connections_new RENAME TO connections;
+        CREATE INDEX connections_token ON connections (token);
+
+        CREATE TABLE last_seen_message_new (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            message_id INTEGER NOT NULL
+        );
+        INSERT INTO last_seen_message_new (id, message_id)
+            SELECT 0, MAX(message_id) FROM last_seen_message HAVING COUNT(*) > 0;
+        DROP TABLE last_seen_message;
+        ALTER TABLE last_seen_message_new RENAME TO last_seen_message;
+
+        CREATE INDEX pending_messages_gotify_id ON pending_messages (gotify_id);
+    ")
+}
+
+/// When each app registered, so the first catch-up knows which messages are meant for it.
+/// Existing registrations get 0, as every message still on the server is undelivered.
+fn add_registration_watermark(tx: &Transaction) -> r2d2_sqlite::rusqlite::Result<()> {
+    tx.execute_batch("
+        ALTER TABLE connections ADD COLUMN registered_at INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE connections ADD COLUMN first_message_id INTEGER NOT NULL DEFAULT 0;
+    ")
+}
+
+/// Replaces the global last seen message with a watermark per Gotify application, and keeps
+/// the state of each delivery so messages that couldn't be deleted aren't delivered twice.
+fn track_deliveries_per_app(tx: &Transaction) -> r2d2_sqlite::rusqlite::Result<()> {
+    tx.execute_batch("
+        ALTER TABLE pending_messages RENAME TO deliveries;
+        ALTER TABLE deliveries ADD COLUMN state TEXT NOT NULL DEFAULT 'pending';
+
+        CREATE TABLE app_watermarks (
+            gotify_id INTEGER PRIMARY KEY,
+            message_id INTEGER NOT NULL
+        );
+        INSERT INTO app_watermarks (gotify_id, message_id)
+            SELECT gotify_id, MAX(first_message_id, COALESCE((SELECT message_id FROM last_seen_message), first_message_id))
+            FROM connections;
+        DROP TABLE last_seen_message;
+    ")
+}
+
+/// Tags registrations, deliveries and watermarks with the Gotify account they belong to.
+/// Application and message ids are only unique within one server, so they're keyed by account too.
+fn add_accounts(tx: &Transaction) -> r2d2_sqlite::rusqlite::Result<()> {
+    tx.execute_batch("
+        CREATE TABLE connections_new (
+            account TEXT NOT NULL,
+            appid TEXT NOT NULL,
+            token TEXT NOT NULL,
+            gotify_token TEXT NOT NULL,
+            gotify_id INTEGER NOT NULL,
+            description TEXT NOT NULL DEFAULT '',
+            dbus_version INTEGER NOT NULL DEFAULT 1,
+            vapid TEXT NOT NULL DEFAULT '',
+            registered_at INTEGER NOT NULL DEFAULT 0,
+            first_message_id INTEGER NOT NULL DEFAULT 0,
+            UNIQUE (account, gotify_id),
+            UNIQUE (appid, token)
+        );
+        INSERT INTO connections_new (account, appid, token, gotify_token, gotify_id, description, dbus_version, vapid, registered_at, first_message_id)
+            SELECT 'default', appid, token, gotify_token, gotify_id, description, dbus_version, vapid, registered_at, first_message_id FROM connections;
+        DROP TABLE connections;
+        ALTER TABLE connections_new RENAME TO connections;
+        CREATE INDEX connections_token ON connections (token);
+
+        CREATE TABLE deliveries_new (
+            account TEXT NOT NULL,
+            message_id INTEGER NOT NULL,
+            gotify_id INTEGER NOT NULL,
+            data TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            state TEXT NOT NULL DEFAULT 'pending',
+            PRIMARY KEY (account, message_id)
+        );
+        INSERT INTO deliveries_new (account, message_id, gotify_id, data, attempts, state)
+            SELECT 'default', message_id, gotify_id, data, attempts, state FROM deliveries;
+        DROP TABLE deliveries;
+        ALTER TABLE deliveries_new RENAME TO deliveries;
+        CREATE INDEX deliveries_gotify_id ON deliveries (account, gotify_id);
+
+        CREATE TABLE app_watermarks_new (
+            account TEXT NOT NULL,
+            gotify_id INTEGER NOT NULL,
+            message_id INTEGER NOT NULL,
+            PRIMARY KEY (account, gotify_id)
+        );
+        INSERT INTO app_watermarks_new (account, gotify_id, message_id)
+            SELECT 'default', gotify_id, message_id FROM app_watermarks;
+        DROP TABLE app_watermarks;
+        ALTER TABLE app_watermarks_new RENAME TO app_watermarks;
+    ")
+}
+
+/// Remembers since when each message's app has been unreachable, so apps that stay gone are unregistered
+/// after a while, however often delivery was retried in the meantime.
+fn add_unreachable_since(tx: &Transaction) -> r2d2_sqlite::rusqlite::Result<()> {
+    tx.execute("ALTER TABLE deliveries ADD COLUMN unreachable_since INTEGER", params![])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(conn: &Connection) -> i32 {
+        conn.pragma_query_value(None, "user_version", |r| r.get(0)).unwrap()
+    }
+
+    /// Applies the migrations up to `version`, leaving the database in that older layout
+    fn migrate_to(conn: &mut Connection, version: usize) {
+        for (from, migration) in MIGRATIONS.iter().enumerate().take(version) {
+            let tx = conn.transaction().unwrap();
+            migration(&tx).unwrap();
+            tx.pragma_update(None, "user_version", &(from as i32 + 1)).unwrap();
+            tx.commit().unwrap();
+        }
+    }
+
+    fn strings(conn: &Connection, query: &str) -> Vec<String> {
+        let mut s = conn.prepare(query).unwrap();
+        let rows = s.query_map(params![], |r| r.get::<_, String>(0)).unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    fn connections(conn: &Connection) -> Vec<String> {
+        strings(conn, "SELECT account || ' ' || appid || ' ' || token || ' ' || gotify_token || ' ' || gotify_id || ' ' || dbus_version FROM connections ORDER BY gotify_id")
+    }
+
+    fn watermarks(conn: &Connection) -> Vec<String> {
+        strings(conn, "SELECT account || ' ' || gotify_id || ' ' || message_id FROM app_watermarks ORDER BY gotify_id")
+    }
+
+    fn deliveries(conn: &Connection) -> Vec<String> {
+        strings(conn, "SELECT account || ' ' || message_id || ' ' || gotify_id || ' ' || data || ' ' || attempts || ' ' || state FROM deliveries ORDER BY message_id")
+    }
+
+    /// Tables and indices, with their definitions
+    fn schema(conn: &Connection) -> Vec<String> {
+        strings(conn, "SELECT name || ': ' || COALESCE(sql, '') FROM sqlite_master ORDER BY name")
+    }
+
+    /// The tables as the daemon created them before the schema was versioned
+    fn unversioned_layout() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE connections (appid TEXT NOT NULL, token TEXT NOT NULL, gotify_token TEXT NOT NULL, gotify_id INTEGER NOT NULL);
+            CREATE TABLE last_seen_message (message_id INTEGER NOT NULL);
+        ").unwrap();
+        conn
+    }
+
+    #[test]
+    fn migrates_the_unversioned_layout() {
+        let mut conn = unversioned_layout();
+        conn.execute_batch("
+            INSERT INTO connections VALUES ('org.example.A', 'token-a', 'gotify-a', 1);
+            INSERT INTO connections VALUES ('org.example.B', 'token-b', 'gotify-b', 2);
+            INSERT INTO connections VALUES ('org.example.A', 'token-a', 'gotify-a-again', 3);
+            INSERT INTO connections VALUES ('org.example.C', 'token-c', 'gotify-c-duplicate', 2);
+            INSERT INTO last_seen_message VALUES (5);
+            INSERT INTO last_seen_message VALUES (9);
+            INSERT INTO last_seen_message VALUES (7);
+        ").unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        // Duplicates of a token or Gotify application keep the oldest registration
+        assert_eq!(connections(&conn), vec![
+            "default org.example.A token-a gotify-a 1 1",
+            "default org.example.B token-b gotify-b 2 1",
+        ]);
+        // Every message up to the highest one seen was handled
+        assert_eq!(watermarks(&conn), vec!["default 1 9", "default 2 9"]);
+        assert!(deliveries(&conn).is_empty());
+    }
+
+    #[test]
+    fn migrates_an_empty_unversioned_database() {
+        let mut conn = unversioned_layout();
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert!(connections(&conn).is_empty());
+        assert!(watermarks(&conn).is_empty());
+    }
+
+    #[test]
+    fn keeps_ad_hoc_columns_and_pending_messages() {
+        let mut conn = unversioned_layout();
+        conn.execute_batch("
+            ALTER TABLE connections ADD COLUMN description TEXT NOT NULL DEFAULT '';
+            ALTER TABLE connections ADD COLUMN dbus_version INTEGER NOT NULL DEFAULT 1;
+            CREATE TABLE pending_messages (message_id INTEGER PRIMARY KEY, gotify_id INTEGER NOT NULL, data TEXT NOT NULL, attempts INTEGER NOT NULL DEFAULT 0);
+            INSERT INTO connections VALUES ('org.example.A', 'token-a', 'gotify-a', 1, 'App A', 2);
+            INSERT INTO pending_messages VALUES (12, 1, 'hello', 3);
+        ").unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert_eq!(connections(&conn), vec!["default org.example.A token-a gotify-a 1 2"]);
+        assert_eq!(deliveries(&conn), vec!["default 12 1 hello 3 pending"]);
+        // Without a last seen message, the watermark starts at the registration
+        assert_eq!(watermarks(&conn), vec!["default 1 0"]);
+    }
+
+    #[test]
+    fn tags_deliveries_and_watermarks_with_the_default_account() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // The layout before add_accounts
+        migrate_to(&mut conn, 5);
+        conn.execute_batch("
+            INSERT INTO connections (appid, token, gotify_token, gotify_id, registered_at, first_message_id)
+                VALUES ('org.example.A', 'token-a', 'gotify-a', 1, 1600000000, 4);
+            INSERT INTO deliveries (message_id, gotify_id, data, state) VALUES (20, 1, 'delivered', 'delivered');
+            INSERT INTO deliveries (message_id, gotify_id, data) VALUES (21, 1, 'pending');
+            INSERT INTO app_watermarks VALUES (1, 21);
+        ").unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert_eq!(connections(&conn), vec!["default org.example.A token-a gotify-a 1 1"]);
+        assert_eq!(deliveries(&conn), vec!["default 20 1 delivered 0 delivered", "default 21 1 pending 0 pending"]);
+        assert_eq!(watermarks(&conn), vec!["default 1 21"]);
+        let first_message_id: i32 = conn.query_row("SELECT first_message_id FROM connections", params![], |r| r.get(0)).unwrap();
+        assert_eq!(first_message_id, 4);
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut conn = unversioned_layout();
+        conn.execute_batch("
+            INSERT INTO connections VALUES ('org.example.A', 'token-a', 'gotify-a', 1);
+            INSERT INTO last_seen_message VALUES (3);
+        ").unwrap();
+        migrate(&mut conn).unwrap();
+        let before = (schema(&conn), connections(&conn), watermarks(&conn), deliveries(&conn));
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert_eq!((schema(&conn), connections(&conn), watermarks(&conn), deliveries(&conn)), before);
+    }
+
+    #[test]
+    fn refuses_newer_schemas() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", &(SCHEMA_VERSION + 1)).unwrap();
+        match migrate(&mut conn) {
+            Err(Error::UnsupportedSchema(version)) => assert_eq!(version, SCHEMA_VERSION + 1),
+            other => panic!("Expected UnsupportedSchema, got {:?}", other),
+        }
+    }
+}
@@ -1,144 +1,598 @@
-use std::error::Error;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use r2d2_sqlite::SqliteConnectionManager;
 use unifiedpush_gotify_lib::{
-    LoginFile,
-    GotifyApplication,
+    Error,
+    Account,
+    DbUpConnection,
+    DBUS_API_V1,
+    DBUS_API_V2,
+    MANAGER_PATH,
     get_connections_with_token,
+    is_valid_bus_name,
 };
-use zbus::Connection;
-use zbus::{dbus_interface, fdo};
-use serde_json::json;
+use unifiedpush_gotify_lib::config::{self, Config};
+use unifiedpush_gotify_lib::gotify::GotifyClient;
+use tokio::sync::{mpsc, watch};
+use zvariant::{OwnedValue, Value};
 use r2d2_sqlite::rusqlite::params;
-use log::{error, warn, info, debug, trace};
+use log::{error, warn, info, debug};
 
-struct Distributor {
-    dbus_conn: &'static Connection,
-    gotify_login: LoginFile,
-    sqlite_pool: Arc<r2d2::Pool<SqliteConnectionManager>> // token -> appid
+use crate::{bus, introspection, manager, shutdown};
+use crate::introspection::{Interface, Method, INTROSPECTABLE, PEER};
+use crate::gotify_receiver::ReceiverHandle;
+
+const DISTRIBUTOR_PATH: &str = "/org/unifiedpush/Distributor";
+
+const DISTRIBUTOR1: Interface = Interface {
+    name: "org.unifiedpush.Distributor1",
+    methods: &[
+        Method {
+            name: "Register",
+            inputs: &[("appid", "s"), ("token", "s"), ("description", "s")],
+            outputs: &[("", "s"), ("", "s")],
+            hidden: false,
+        },
+        // Connectors written against the original spec call `Register` without a description
+        Method { name: "Register", inputs: &[("appid", "s"), ("token", "s")], outputs: &[("", "s"), ("", "s")], hidden: true },
+        Method { name: "Unregister", inputs: &[("token", "s")], outputs: &[], hidden: false },
+    ],
+    signals: &[],
+};
+
+const DISTRIBUTOR2: Interface = Interface {
+    name: "org.unifiedpush.Distributor2",
+    methods: &[
+        Method { name: "Register", inputs: &[("args", "a{sv}")], outputs: &[("", "a{sv}")], hidden: false },
+        Method { name: "Unregister", inputs: &[("args", "a{sv}")], outputs: &[("", "a{sv}")], hidden: false },
+    ],
+    signals: &[],
+};
+
+const INTERFACES: &[&Interface] = &[&DISTRIBUTOR1, &DISTRIBUTOR2, &INTROSPECTABLE, &PEER];
+
+/// The objects the daemon serves, the paths above them can only be introspected
+pub(crate) const OBJECT_PATHS: &[&str] = &[DISTRIBUTOR_PATH, MANAGER_PATH];
+
+/// Serves `org.unifiedpush.Distributor1`, where arguments and results are passed as plain strings,
+/// and `org.unifiedpush.Distributor2`, where they are passed as `a{sv}` dictionaries
+#[derive(Clone)]
+pub(crate) struct Distributor {
+    pub(crate) dbus_conn: &'static zbus::azync::Connection,
+    bus_name: String,
+    pub(crate) accounts: Arc<Vec<Account>>,
+    /// Clients of the accounts, by account name
+    gotify: Arc<HashMap<String, GotifyClient>>,
+    /// Routes registrations to accounts, replaced when the configuration is reloaded
+    config: Arc<RwLock<Arc<Config>>>,
+    pub(crate) config_source: Arc<config::Source>,
+    pub(crate) receivers: Arc<Vec<ReceiverHandle>>,
+    pub(crate) sqlite_pool: Arc<r2d2::Pool<SqliteConnectionManager>>, // token -> appid
+    /// The user the daemon runs as, the only one besides root allowed to use the management interface
+    pub(crate) uid: Option<u32>,
+    /// Held while registering or unregistering a token, by token
+    token_locks: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+pub(crate) enum Reply {
+    Empty,
+    Str(String),
+    V1((String, String)),
+    V2(HashMap<&'static str, Value<'static>>),
+    List(Vec<HashMap<&'static str, Value<'static>>>),
+    Strings(Vec<&'static str>),
+    States(HashMap<String, &'static str>),
+    Error(&'static str, String),
+}
+
+enum Registration {
+    NewEndpoint,
+    Refused(&'static str),
+    DatabaseError,
+    ServerError,
+}
+
+impl Registration {
+    fn to_v1(&self) -> (String, String) {
+        let (result, reason) = match self {
+            Registration::NewEndpoint => ("NEW_ENDPOINT", ""),
+            Registration::Refused(reason) => ("REGISTRATION_REFUSED", *reason),
+            Registration::DatabaseError => ("REGISTRATION_FAILED", "Distributor database error"),
+            Registration::ServerError => ("REGISTRATION_FAILED", "Gotify server error"),
+        };
+        (result.to_owned(), reason.to_owned())
+    }
+
+    fn to_v2(&self) -> HashMap<&'static str, Value<'static>> {
+        let mut result = HashMap::new();
+        let reason = match self {
+            Registration::NewEndpoint => None,
+            Registration::Refused(_) => Some("UNAUTHORIZED"),
+            Registration::DatabaseError => Some("INTERNAL_ERROR"),
+            Registration::ServerError => Some("NETWORK"),
+        };
+        if let Some(reason) = reason {
+            result.insert("success", Value::from("REGISTRATION_FAILED"));
+            result.insert("reason", Value::from(reason));
+        } else {
+            result.insert("success", Value::from("REGISTRATION_SUCCEEDED"));
+        }
+        result
+    }
 }
 
-fn send_new_endpoint(conn: &zbus::Connection, appid: &str, token: &str, endpoint: &str) {
-    let result = conn.send_message(zbus::Message::method(
-        Some("org.unifiedpush.Distributor.gotify"), 
-        Some(appid),
-        "/org/unifiedpush/Connector",
-        Some("org.unifiedpush.Connector1"),
-        "NewEndpoint",
-        &(token, endpoint)).unwrap());
-    if let Err(e) = result {
-        error!("Failed to send new endpoint: {:?}", e);
+fn dict_str<'a>(args: &'a HashMap<String, OwnedValue>, key: &str) -> Option<&'a str> {
+    match args.get(key).map(|v| &**v) {
+        Some(Value::Str(s)) => Some(s.as_str()),
+        _ => None
     }
 }
 
+/// Pushes are delivered to the app's well-known bus name, so the appid must be a valid one
+fn is_valid_appid(appid: &str) -> bool {
+    is_valid_bus_name(appid)
+}
+
+async fn send_new_endpoint(conn: &zbus::azync::Connection, sender: &str, dbus_version: i32, appid: &str, token: &str, endpoint: &str) -> Result<(), Error> {
+    let message = if dbus_version == DBUS_API_V2 {
+        let mut args = HashMap::new();
+        args.insert("token", Value::from(token));
+        args.insert("endpoint", Value::from(endpoint));
+        zbus::Message::method(
+            Some(sender),
+            Some(appid),
+            "/org/unifiedpush/Connector",
+            Some("org.unifiedpush.Connector2"),
+            "NewEndpoint",
+            &args)?
+    } else {
+        zbus::Message::method(
+            Some(sender),
+            Some(appid),
+            "/org/unifiedpush/Connector",
+            Some("org.unifiedpush.Connector1"),
+            "NewEndpoint",
+            &(token, endpoint))?
+    };
+    conn.send_message(message).await?;
+    Ok(())
+}
+
+async fn send_unregistered(conn: &zbus::azync::Connection, sender: &str, dbus_version: i32, appid: &str, token: &str) -> Result<(), Error> {
+    let message = if dbus_version == DBUS_API_V2 {
+        let mut args = HashMap::new();
+        args.insert("token", Value::from(token));
+        zbus::Message::method(
+            Some(sender),
+            Some(appid),
+            "/org/unifiedpush/Connector",
+            Some("org.unifiedpush.Connector2"),
+            "Unregistered",
+            &args)?
+    } else {
+        zbus::Message::method(
+            Some(sender),
+            Some(appid),
+            "/org/unifiedpush/Connector",
+            Some("org.unifiedpush.Connector1"),
+            "Unregistered",
+            &(token))?
+    };
+    conn.send_message(message).await?;
+    Ok(())
+}
+
+fn endpoint(gotify: &GotifyClient, gotify_token: &str) -> String {
+    format!("{}/UP?token={}", gotify.base_url(), gotify_token)
+}
+
+pub(crate) fn invalid_args(e: impl std::fmt::Display) -> Reply {
+    Reply::Error("org.freedesktop.DBus.Error.InvalidArgs", e.to_string())
+}
 
-#[dbus_interface(name = "org.unifiedpush.Distributor1")]
 impl Distributor {
-    fn register(&mut self, appid: &str, token: &str) -> (String, String) {
+    /// Calls with the same token run one after another, so an app calling `Register` again before
+    /// the first call is answered doesn't get a second Gotify application
+    fn token_lock(&self, token: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.token_locks.lock().unwrap_or_else(|e| e.into_inner());
+        // Drop the locks of finished calls, so tokens don't pile up
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(token.to_owned()).or_default().clone()
+    }
+
+    fn gotify(&self, account: &str) -> Option<&GotifyClient> {
+        self.gotify.get(account)
+    }
+
+    pub(crate) fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub(crate) fn set_config(&self, config: Config) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+
+    pub(crate) fn gotify_url(&self, account: &str) -> Option<String> {
+        self.gotify(account).map(|gotify| gotify.base_url().to_owned())
+    }
+
+    /// The push endpoint of a registration, if its account is still configured
+    pub(crate) fn endpoint_of(&self, connection: &DbUpConnection) -> Option<String> {
+        self.gotify(&connection.account).map(|gotify| endpoint(gotify, &connection.gotify_token))
+    }
+
+    /// Sends the app its endpoint again, as if it had registered
+    pub(crate) async fn resend_endpoint(&self, connection: &DbUpConnection) -> Result<(), Error> {
+        let endpoint = self.endpoint_of(connection)
+            .ok_or_else(|| Error::Config(format!("Account {} isn't configured anymore", connection.account)))?;
+        send_new_endpoint(self.dbus_conn, &self.bus_name, connection.dbus_version, &connection.appid, &connection.token, &endpoint).await
+    }
+
+    fn update_registration(&self, token: &str, description: &str, dbus_version: i32, vapid: &str) -> Result<(), Error> {
+        self.sqlite_pool.get()?.execute(
+            "UPDATE connections SET description=?, dbus_version=?, vapid=? WHERE token=?",
+            params![description, dbus_version, vapid, token])?;
+        Ok(())
+    }
+
+    fn insert_registration(&self, connection: &DbUpConnection) -> Result<(), Error> {
+        let mut conn = self.sqlite_pool.get()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO connections (account, appid, token, gotify_token, gotify_id, description, dbus_version, vapid, registered_at, first_message_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                connection.account, connection.appid, connection.token, connection.gotify_token, connection.gotify_id,
+                connection.description, connection.dbus_version, connection.vapid, connection.registered_at, connection.first_message_id])?;
+        tx.execute(
+            "INSERT OR REPLACE INTO app_watermarks (account, gotify_id, message_id) VALUES (?, ?, ?)",
+            params![connection.account, connection.gotify_id, connection.first_message_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_registrations(&self, list: &[DbUpConnection]) -> Result<(), Error> {
+        let mut conn = self.sqlite_pool.get()?;
+        let tx = conn.transaction()?;
+        for row in list {
+            tx.execute("DELETE FROM connections WHERE account=? AND gotify_id=?", params![row.account, row.gotify_id])?;
+            tx.execute("DELETE FROM deliveries WHERE account=? AND gotify_id=?", params![row.account, row.gotify_id])?;
+            tx.execute("DELETE FROM app_watermarks WHERE account=? AND gotify_id=?", params![row.account, row.gotify_id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn register_app(&self, dbus_version: i32, appid: &str, token: &str, description: &str, vapid: &str) -> Registration {
         debug!("Registering app {} with token {}", appid, token);
-        let client = reqwest::blocking::Client::new();
+        if token.is_empty() {
+            warn!("Refusing registration of {} with an empty token", appid);
+            return Registration::Refused("Empty token");
+        }
+        if !is_valid_appid(appid) {
+            warn!("Refusing registration of invalid appid {}", appid);
+            return Registration::Refused("Application ID is not a valid D-Bus name");
+        }
+        let lock = self.token_lock(token);
+        let _registering = lock.lock().await;
         // Check if app already exists on Gotify
-        if let Ok(list) = get_connections_with_token(&self.sqlite_pool, token).map_err(|_|()) {
-            if let Some(c) = list.iter().find(|c| c.appid == appid && c.token == token) {
-                debug!("App was already registered to gotify, returning existing endpoint");
-                send_new_endpoint(self.dbus_conn, appid, token, format!("{}/UP?token={}", self.gotify_login.gotify_base_url, c.gotify_token).as_str());
-                (
-                    "NEW_ENDPOINT".to_string(),
-                    String::new()
-                )
-            } else {
-                // Add new app to Gotify server
-                debug!("App doesn't exist, adding new app to Gotify server");
-                let response = client.post(format!("{}/application", self.gotify_login.gotify_base_url).as_str())
-                    .header("Content-Type", "application/json")
-                    .header("X-Gotify-Key", &self.gotify_login.gotify_device_token)
-                    .json(&json!{{
-                        "name": appid,
-                        "token": token
-                    }})
-                    .send()
-                    .map_err(|_| ())
-                    .and_then(|r| if r.status() == 200 { r.json::<GotifyApplication>().map_err(|_| ()) } else { Err(()) });
-                if let Ok(response) = response {
-                    // Try writing to sqlite database
-                    debug!("Gotify registration succeeded, adding to sqlite database");
-                    if let Ok(_) = self.sqlite_pool.get()
-                        .map(|c| c.execute("INSERT INTO connections (appid, token, gotify_token, gotify_id) VALUES (?, ?, ?, ?)", params![appid, token, response.token, response.id])) {
-                        info!("Register new app {}", appid);
-                        send_new_endpoint(self.dbus_conn, appid, token, format!("{}/UP?token={}", self.gotify_login.gotify_base_url, response.token).as_str());
-                        debug!("App registration succeeded");
-                        (
-                            "NEW_ENDPOINT".to_string(),
-                            String::new()
-                        )
-                    } else {
-                        error!("Writing to sqlite database failed!");
-                        // Delete the newly added connection from Gotify, because writing to sqlite failed
-                        client.delete(format!("{}/application/{}", self.gotify_login.gotify_base_url, response.id).as_str())
-                            .header("X-Gotify-Key", &self.gotify_login.gotify_device_token)
-                            .send();
-                        (
-                            "REGISTRATION_FAILED".to_owned(),
-                            "Distributor database error".to_owned()
-                        )
-                    }
-                } else {
-                    error!("Registering with Gotify server failed!");
-                    (
-                        "REGISTRATION_FAILED".to_owned(),
-                        "Gotify server error".to_owned()
-                    )
+        let list = match get_connections_with_token(&self.sqlite_pool, token) {
+            Ok(list) => list,
+            Err(e) => {
+                error!("Failed to look up registrations of {}: {}", appid, e);
+                return Registration::DatabaseError;
+            }
+        };
+        if list.iter().any(|c| c.appid != appid) {
+            warn!("Refusing registration of {}, token is in use by another app", appid);
+            return Registration::Refused("Token is already registered by another application");
+        }
+        if let Some(c) = list.iter().find(|c| c.appid == appid && c.token == token) {
+            debug!("App was already registered to gotify, returning existing endpoint");
+            let gotify = match self.gotify(&c.account) {
+                Some(gotify) => gotify,
+                None => {
+                    error!("{} is registered with account {}, which isn't configured anymore", appid, c.account);
+                    return Registration::ServerError;
+                }
+            };
+            if c.description != description || c.dbus_version != dbus_version || c.vapid != vapid {
+                if let Err(e) = self.update_registration(token, description, dbus_version, vapid) {
+                    warn!("Failed to update registration of {}: {}", appid, e);
                 }
             }
-        } else {
-            error!("Reading from sqlite database failed!");
-            (
-                "REGISTRATION_FAILED".to_owned(),
-                "Distributor database error".to_owned()
-            )
+            if let Err(e) = send_new_endpoint(self.dbus_conn, &self.bus_name, dbus_version, appid, token, &endpoint(gotify, &c.gotify_token)).await {
+                error!("Failed to send new endpoint to {}: {}", appid, e);
+            }
+            return Registration::NewEndpoint;
+        }
+
+        // Add new app to Gotify server
+        let config = self.config();
+        let account = config.account_for(&self.accounts, appid);
+        let gotify = match self.gotify(account) {
+            Some(gotify) => gotify,
+            None => {
+                error!("Account {} for {} isn't configured", account, appid);
+                return Registration::ServerError;
+            }
+        };
+        debug!("App doesn't exist, adding new app to Gotify server of account {}", account);
+        // Messages newer than this are meant for the app, even before the daemon has seen any
+        let first_message_id = match gotify.list_messages(1, None).await {
+            Ok(page) => page.messages.first().map_or(0, |m| m.id),
+            Err(e) => {
+                warn!("Failed to fetch the newest message id: {}", e);
+                0
+            }
+        };
+        let registered_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        let application = match gotify.create_application(appid, description).await {
+            Ok(application) => application,
+            Err(e) => {
+                error!("Registering {} with Gotify server failed: {}", appid, e);
+                return Registration::ServerError;
+            }
+        };
+        debug!("Gotify registration succeeded, adding to sqlite database");
+        let connection = DbUpConnection {
+            account: account.to_owned(),
+            appid: appid.to_owned(),
+            token: token.to_owned(),
+            gotify_id: application.id,
+            gotify_token: application.token.clone(),
+            description: description.to_owned(),
+            dbus_version,
+            vapid: vapid.to_owned(),
+            registered_at,
+            first_message_id,
+        };
+        if let Err(e) = self.insert_registration(&connection) {
+            error!("Failed to store registration of {}: {}", appid, e);
+            // Delete the newly added connection from Gotify, because writing to sqlite failed
+            if let Err(e) = gotify.delete_application(application.id).await {
+                error!("Failed to delete Gotify application {}: {}", application.id, e);
+            }
+            return Registration::DatabaseError;
         }
+        info!("Register new app {} with account {}", appid, account);
+        if let Err(e) = send_new_endpoint(self.dbus_conn, &self.bus_name, dbus_version, appid, token, &endpoint(gotify, &application.token)).await {
+            error!("Failed to send new endpoint to {}: {}", appid, e);
+        }
+        debug!("App registration succeeded");
+        Registration::NewEndpoint
     }
 
-    fn unregister(&mut self, #[zbus(header)] _header: zbus::MessageHeader<'_>, token: &str) {
+    pub(crate) async fn unregister_app(&self, token: &str) {
         debug!("Unregistering app with token {}", token);
-        let client = reqwest::blocking::Client::new();
-        if let Ok(list) = get_connections_with_token(&self.sqlite_pool, token) {
-            for row in list {
-                client.delete(format!("{}/application/{}", self.gotify_login.gotify_base_url, row.gotify_id).as_str())
-                    .header("X-Gotify-Key", &self.gotify_login.gotify_device_token)
-                    .send();
+        let lock = self.token_lock(token);
+        let _unregistering = lock.lock().await;
+        let list = match get_connections_with_token(&self.sqlite_pool, token) {
+            Ok(list) => list,
+            Err(e) => {
+                error!("Failed to look up registrations with token {}: {}", token, e);
+                return;
+            }
+        };
+        for row in &list {
+            match self.gotify(&row.account) {
+                Some(gotify) => if let Err(e) = gotify.delete_application(row.gotify_id).await {
+                    error!("Failed to delete Gotify application {} of account {}: {}", row.gotify_id, row.account, e);
+                },
+                None => warn!("Can't delete Gotify application {}, account {} isn't configured anymore", row.gotify_id, row.account),
             }
-            self.sqlite_pool.get().map_err(|_|())
-                .and_then(|c| c.execute("DELETE FROM connections WHERE token=?", &[token]).map_err(|_|()));
         }
+        if let Err(e) = self.delete_registrations(&list) {
+            error!("Failed to delete registrations with token {}: {}", token, e);
+        }
+        for row in &list {
+            if let Err(e) = send_unregistered(self.dbus_conn, &self.bus_name, row.dbus_version, &row.appid, token).await {
+                error!("Failed to send unregistered to {}: {}", row.appid, e);
+            }
+        }
+    }
+
+    async fn register_v2(&self, args: HashMap<String, OwnedValue>) -> Reply {
+        let appid = match dict_str(&args, "service") {
+            Some(appid) => appid,
+            None => return invalid_args("Missing service"),
+        };
+        let token = match dict_str(&args, "token") {
+            Some(token) => token,
+            None => return invalid_args("Missing token"),
+        };
+        let description = dict_str(&args, "description").unwrap_or("");
+        let vapid = dict_str(&args, "vapid").unwrap_or("");
+        Reply::V2(self.register_app(DBUS_API_V2, appid, token, description, vapid).await.to_v2())
+    }
+
+    async fn unregister_v2(&self, args: HashMap<String, OwnedValue>) -> Reply {
+        match dict_str(&args, "token") {
+            Some(token) => {
+                self.unregister_app(token).await;
+                Reply::V2(HashMap::new())
+            }
+            None => invalid_args("Missing token"),
+        }
+    }
+
+    /// Methods are matched on their signature as well as their name, as `Register` of
+    /// `org.unifiedpush.Distributor1` is called with and without a description
+    async fn handle_call(&self, message: &zbus::Message) -> Reply {
+        let header = match message.header() {
+            Ok(header) => header,
+            Err(e) => return invalid_args(e),
+        };
+        let interface = header.interface().ok().flatten().unwrap_or("");
+        let member = header.member().ok().flatten().unwrap_or("");
+        let signature = message.body_signature().map(|s| s.as_str().to_owned()).unwrap_or_default();
+        if let Err(reply) = introspection::check_call(INTERFACES, interface, member, &signature) {
+            return reply;
+        }
+        match (interface, member, signature.as_str()) {
+            ("org.unifiedpush.Distributor1", "Register", "sss") => match message.body::<(&str, &str, &str)>() {
+                Ok((appid, token, description)) => Reply::V1(self.register_app(DBUS_API_V1, appid, token, description, "").await.to_v1()),
+                Err(e) => invalid_args(e),
+            },
+            ("org.unifiedpush.Distributor1", "Register", "ss") => match message.body::<(&str, &str)>() {
+                Ok((appid, token)) => Reply::V1(self.register_app(DBUS_API_V1, appid, token, "", "").await.to_v1()),
+                Err(e) => invalid_args(e),
+            },
+            ("org.unifiedpush.Distributor1", "Unregister", "s") => match message.body::<&str>() {
+                Ok(token) => {
+                    self.unregister_app(token).await;
+                    Reply::Empty
+                }
+                Err(e) => invalid_args(e),
+            },
+            ("org.unifiedpush.Distributor2", "Register", "a{sv}") => match message.body::<HashMap<String, OwnedValue>>() {
+                Ok(args) => self.register_v2(args).await,
+                Err(e) => invalid_args(e),
+            },
+            ("org.unifiedpush.Distributor2", "Unregister", "a{sv}") => match message.body::<HashMap<String, OwnedValue>>() {
+                Ok(args) => self.unregister_v2(args).await,
+                Err(e) => invalid_args(e),
+            },
+            ("org.freedesktop.DBus.Introspectable", "Introspect", "") => Reply::Str(introspection::xml(DISTRIBUTOR_PATH, INTERFACES, OBJECT_PATHS)),
+            ("org.freedesktop.DBus.Peer", "Ping", "") => Reply::Empty,
+            _ => introspection::unknown_method(interface, member, &signature),
+        }
+    }
+
+    pub(crate) async fn reply(&self, message: &zbus::Message, reply: Reply) {
+        let result = match reply {
+            Reply::Empty => self.dbus_conn.reply(message, &()).await,
+            Reply::Str(s) => self.dbus_conn.reply(message, &s).await,
+            Reply::V1(body) => self.dbus_conn.reply(message, &body).await,
+            Reply::V2(body) => self.dbus_conn.reply(message, &body).await,
+            Reply::List(body) => self.dbus_conn.reply(message, &body).await,
+            Reply::Strings(body) => self.dbus_conn.reply(message, &body).await,
+            Reply::States(body) => self.dbus_conn.reply(message, &body).await,
+            Reply::Error(name, description) => self.dbus_conn.reply_error(message, name, &description).await,
+        };
+        if let Err(e) = result {
+            error!("Failed to reply to D-Bus call: {}", e);
+        }
+    }
+}
+
+fn is_for_object(message: &zbus::Message, path: &str) -> bool {
+    message.header().map_or(false, |h| h.path().ok().flatten().map_or(false, |p| p.as_str() == path))
+}
+
+/// Answers `Introspect` on the paths above the objects, so tools like `busctl tree` can find them
+fn handle_parent_call(message: &zbus::Message) -> Reply {
+    let header = match message.header() {
+        Ok(header) => header,
+        Err(e) => return invalid_args(e),
+    };
+    let path = header.path().ok().flatten().map(|p| p.as_str().to_owned()).unwrap_or_default();
+    if introspection::children(&path, OBJECT_PATHS).is_empty() {
+        return Reply::Error("org.freedesktop.DBus.Error.UnknownObject", "Unknown object path".to_owned());
+    }
+    let interface = header.interface().ok().flatten().unwrap_or("");
+    let member = header.member().ok().flatten().unwrap_or("");
+    let signature = message.body_signature().map(|s| s.as_str().to_owned()).unwrap_or_default();
+    match introspection::check_call(&[&INTROSPECTABLE], interface, member, &signature) {
+        Ok(()) => Reply::Str(introspection::xml(&path, &[&INTROSPECTABLE], OBJECT_PATHS)),
+        Err(reply) => reply,
     }
 }
 
-pub fn run(
+/// Each method call is handled in its own task, so a slow Gotify server only delays the call waiting on it.
+/// On shutdown, the bus name is released first, calls that were queued by then are refused,
+/// and the running ones are answered before returning.
+pub async fn run(
     sqlite_pool: Arc<r2d2::Pool<SqliteConnectionManager>>,
-    dbus_conn: &'static Connection,
-    login_file: LoginFile) -> Result<(), Box<dyn Error>> {
-        
-    debug!("Starting D-Bus registration thread");
-    fdo::DBusProxy::new(&dbus_conn)?.request_name(
-        "org.unifiedpush.Distributor.gotify",
-        fdo::RequestNameFlags::ReplaceExisting.into(),
-    )?;
-    debug!("Successfully requested D-Bus name");
+    dbus_conn: &'static zbus::azync::Connection,
+    accounts: Vec<Account>,
+    config: Config,
+    config_source: config::Source,
+    receivers: Vec<ReceiverHandle>,
+    mut shutdown: watch::Receiver<bool>) -> Result<(), Error> {
 
-    let mut object_server = zbus::ObjectServer::new(&dbus_conn);
+    debug!("Starting D-Bus registration receiver");
+    let bus_name = config.bus_name.clone();
+    // The receivers may already be waiting for replies, which only come in while the connection is read
+    bus::call_reading(dbus_conn, bus::request_name(dbus_conn, &bus_name, bus::DBUS_NAME_FLAG_REPLACE_EXISTING)).await?;
+    debug!("Successfully requested D-Bus name");
+    let uid = match dbus_conn.unique_name() {
+        Some(name) => bus::call_reading(dbus_conn, bus::connection_unix_user(dbus_conn, name)).await.ok(),
+        None => None,
+    };
+    if uid.is_none() {
+        warn!("Couldn't find out which user the daemon runs as, only root can use the management interface");
+    }
 
-    let r = Distributor {
-        dbus_conn: dbus_conn,
-        gotify_login: login_file,
-        sqlite_pool: sqlite_pool
+    let distributor = Distributor {
+        dbus_conn,
+        bus_name: bus_name.clone(),
+        gotify: Arc::new(accounts.iter().map(|a| (a.name.clone(), GotifyClient::from_login(&a.login))).collect()),
+        accounts: Arc::new(accounts),
+        config: Arc::new(RwLock::new(Arc::new(config))),
+        config_source: Arc::new(config_source),
+        receivers: Arc::new(receivers),
+        sqlite_pool,
+        uid,
+        token_locks: Arc::new(Mutex::new(HashMap::new())),
     };
-    object_server.at("/org/unifiedpush/Distributor", r)?;
-    debug!("Successfully registered interface on D-Bus path");
+    for receiver in distributor.receivers.iter() {
+        tokio::spawn(manager::publish_state_changes(dbus_conn, receiver.clone()));
+    }
+    // Every call task holds a sender, so the receiver only closes once they've all finished
+    let (in_flight, mut finished) = mpsc::channel::<()>(1);
+    loop {
+        let message = tokio::select! {
+            message = bus::next_method_call(dbus_conn) => message,
+            _ = shutdown::requested(&mut shutdown) => break,
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(zbus::Error::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+            Err(err) => {
+                error!("D-Bus error: {}", err);
+                continue;
+            }
+        };
+        let distributor = distributor.clone();
+        let in_flight = in_flight.clone();
+        tokio::spawn(async move {
+            let _in_flight = in_flight;
+            let reply = if is_for_object(&message, DISTRIBUTOR_PATH) {
+                distributor.handle_call(&message).await
+            } else if is_for_object(&message, MANAGER_PATH) {
+                manager::handle_call(&distributor, &message).await
+            } else {
+                handle_parent_call(&message)
+            };
+            distributor.reply(&message, reply).await;
+        });
+    }
+
+    match bus::call_reading(dbus_conn, bus::release_name(dbus_conn, &bus_name)).await {
+        Ok(_) => info!("Released D-Bus name"),
+        Err(e) => error!("Failed to release D-Bus name: {}", e),
+    }
+    // The bus answers ReleaseName after routing every call sent to the name before,
+    // so callers of queued calls get an error instead of waiting for a timeout.
+    // The connection is still read until the running calls finish, as they may wait for replies.
+    debug!("Waiting for running D-Bus calls to finish");
+    drop(in_flight);
     loop {
-        if let Err(err) = object_server.try_handle_next() {
-            error!("D-Bus error: {}", err);
+        tokio::select! {
+            _ = finished.recv() => break,
+            message = bus::next_method_call(dbus_conn) => match message {
+                Ok(message) => distributor.reply(&message, Reply::Error(
+                    "org.freedesktop.DBus.Error.Failed",
+                    "The distributor is shutting down".to_owned())).await,
+                Err(zbus::Error::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    finished.recv().await;
+                    break;
+                }
+                Err(err) => error!("D-Bus error: {}", err),
+            },
         }
     }
+    Ok(())
 }
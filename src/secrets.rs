@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use zbus::fdo;
+use zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+use log::{info, warn, debug};
+
+use crate::Error;
+
+const SERVICE: &str = "org.freedesktop.secrets";
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+const COLLECTION_INTERFACE: &str = "org.freedesktop.Secret.Collection";
+const ITEM_INTERFACE: &str = "org.freedesktop.Secret.Item";
+const PROMPT_INTERFACE: &str = "org.freedesktop.Secret.Prompt";
+
+/// Object paths the Secret Service returns instead of an object, or when no prompt is needed
+const NO_OBJECT: &str = "/";
+
+/// Prompts the user hasn't answered by then are dismissed
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Tokens are looked up by these attributes, along with the account name and server URL
+const APPLICATION: &str = "unifiedpush_gotify";
+
+/// A secret as the Secret Service passes it: session, parameters, value and content type
+type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+fn object_path(path: &str) -> Result<ObjectPath<'_>, Error> {
+    ObjectPath::try_from(path).map_err(|e| Error::SecretService(format!("Invalid object path {}: {}", path, e)))
+}
+
+fn attributes<'a>(account: &'a str, base_url: &'a str) -> HashMap<&'static str, &'a str> {
+    let mut attributes = HashMap::new();
+    attributes.insert("application", APPLICATION);
+    attributes.insert("account", account);
+    attributes.insert("gotify_base_url", base_url);
+    attributes
+}
+
+/// Stores device tokens in the user's keyring through `org.freedesktop.secrets` on the session bus.
+/// Secrets are passed unencrypted, which is fine as the session bus only connects the user's own processes.
+pub struct SecretService {
+    conn: zbus::Connection,
+    session: OwnedObjectPath,
+}
+
+impl SecretService {
+    pub fn connect() -> Result<Self, Error> {
+        let conn = zbus::Connection::new_session()?;
+        let (_, session): (OwnedValue, OwnedObjectPath) = conn.call_method(
+            Some(SERVICE), SERVICE_PATH, Some(SERVICE_INTERFACE), "OpenSession", &("plain", Value::from("")))?.body()?;
+        debug!("Opened Secret Service session {}", session.as_str());
+        Ok(SecretService { conn, session })
+    }
+
+    /// Dismisses the prompt once `PROMPT_TIMEOUT` passed, unless `done` is dropped before.
+    /// Dismissing makes the Secret Service complete the prompt, which ends the wait for it.
+    fn dismiss_on_timeout(&self, prompt: &str, done: mpsc::Receiver<()>) -> Arc<AtomicBool> {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let conn = self.conn.clone();
+        let prompt = prompt.to_owned();
+        let flag = timed_out.clone();
+        thread::spawn(move || {
+            if done.recv_timeout(PROMPT_TIMEOUT) != Err(mpsc::RecvTimeoutError::Timeout) {
+                return;
+            }
+            flag.store(true, Ordering::SeqCst);
+            if let Err(e) = conn.call_method(Some(SERVICE), prompt.as_str(), Some(PROMPT_INTERFACE), "Dismiss", &()) {
+                warn!("Failed to dismiss the keyring prompt: {}", e);
+            }
+        });
+        timed_out
+    }
+
+    /// Shows the prompt the Secret Service asked for, and waits until the user completed it or `PROMPT_TIMEOUT` passed
+    fn prompt(&self, prompt: &str) -> Result<(), Error> {
+        info!("Waiting for the keyring to be unlocked");
+        let rule = format!("type='signal',interface='{}',member='Completed',path='{}'", PROMPT_INTERFACE, prompt);
+        fdo::DBusProxy::new(&self.conn)?.add_match(&rule)?;
+        self.conn.call_method(Some(SERVICE), prompt, Some(PROMPT_INTERFACE), "Prompt", &"")?;
+        // Dropped on return, which stops the timeout
+        let (_waiting, done) = mpsc::channel();
+        let timed_out = self.dismiss_on_timeout(prompt, done);
+        // Other messages stay queued, the reply to dismissing the prompt among them
+        let message = self.conn.receive_specific(|message| {
+            let header = message.header()?;
+            Ok(header.message_type()? == zbus::MessageType::Signal
+                && header.member()? == Some("Completed")
+                && header.path()?.map_or(false, |p| p.as_str() == prompt))
+        })?;
+        let (dismissed, _): (bool, OwnedValue) = message.body()?;
+        if dismissed && timed_out.load(Ordering::SeqCst) {
+            Err(Error::SecretService(format!("The keyring prompt wasn't answered within {} seconds", PROMPT_TIMEOUT.as_secs())))
+        } else if dismissed {
+            Err(Error::SecretService("The keyring prompt was dismissed".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn unlock(&self, paths: &[OwnedObjectPath]) -> Result<Vec<OwnedObjectPath>, Error> {
+        let (unlocked, prompt): (Vec<OwnedObjectPath>, OwnedObjectPath) = self.conn.call_method(
+            Some(SERVICE), SERVICE_PATH, Some(SERVICE_INTERFACE), "Unlock", &paths)?.body()?;
+        if prompt.as_str() == NO_OBJECT {
+            return Ok(unlocked);
+        }
+        // Completing the prompt unlocks everything that was asked for
+        self.prompt(prompt.as_str())?;
+        Ok(paths.to_vec())
+    }
+
+    /// The unlocked default collection, which new items are stored in
+    fn default_collection(&self) -> Result<OwnedObjectPath, Error> {
+        let collection: OwnedObjectPath = self.conn.call_method(
+            Some(SERVICE), SERVICE_PATH, Some(SERVICE_INTERFACE), "ReadAlias", &"default")?.body()?;
+        if collection.as_str() == NO_OBJECT {
+            return Err(Error::SecretService("There is no default keyring".to_owned()));
+        }
+        self.unlock(&[collection.clone()])?;
+        Ok(collection)
+    }
+
+    pub fn store_token(&self, account: &str, base_url: &str, token: &str) -> Result<(), Error> {
+        let collection = self.default_collection()?;
+        let mut properties = HashMap::new();
+        properties.insert("org.freedesktop.Secret.Item.Label", Value::from(format!("UnifiedPush Gotify device token ({})", account)));
+        properties.insert("org.freedesktop.Secret.Item.Attributes", Value::from(attributes(account, base_url)));
+        let secret = (object_path(self.session.as_str())?, Vec::<u8>::new(), token.as_bytes(), "text/plain");
+        let (_, prompt): (OwnedObjectPath, OwnedObjectPath) = self.conn.call_method(
+            Some(SERVICE), collection.as_str(), Some(COLLECTION_INTERFACE), "CreateItem", &(properties, secret, true))?.body()?;
+        if prompt.as_str() != NO_OBJECT {
+            self.prompt(prompt.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// The unlocked items holding the token of the account
+    fn find_items(&self, account: &str, base_url: &str) -> Result<Vec<OwnedObjectPath>, Error> {
+        let (mut items, locked): (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) = self.conn.call_method(
+            Some(SERVICE), SERVICE_PATH, Some(SERVICE_INTERFACE), "SearchItems", &attributes(account, base_url))?.body()?;
+        if !locked.is_empty() {
+            items.extend(self.unlock(&locked)?);
+        }
+        Ok(items)
+    }
+
+    /// Returns `None` if the keyring has no token for the account
+    pub fn load_token(&self, account: &str, base_url: &str) -> Result<Option<String>, Error> {
+        let item = match self.find_items(account, base_url)?.into_iter().next() {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let mut secrets: HashMap<OwnedObjectPath, Secret> = self.conn.call_method(
+            Some(SERVICE), SERVICE_PATH, Some(SERVICE_INTERFACE), "GetSecrets", &(vec![item.clone()], &self.session))?.body()?;
+        let (_, _, value, _) = secrets.remove(&item)
+            .ok_or_else(|| Error::SecretService(format!("No secret returned for {}", item.as_str())))?;
+        String::from_utf8(value)
+            .map(Some)
+            .map_err(|_| Error::SecretService(format!("The token of account {} isn't valid UTF-8", account)))
+    }
+
+    /// Removes the token of the account from the keyring, if it has one
+    pub fn delete_token(&self, account: &str, base_url: &str) -> Result<(), Error> {
+        for item in self.find_items(account, base_url)? {
+            let prompt: OwnedObjectPath = self.conn.call_method(
+                Some(SERVICE), item.as_str(), Some(ITEM_INTERFACE), "Delete", &())?.body()?;
+            if prompt.as_str() != NO_OBJECT {
+                self.prompt(prompt.as_str())?;
+            }
+        }
+        Ok(())
+    }
+}
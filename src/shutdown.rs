@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use log::{error, info};
+
+/// Requests shutdown on the first SIGTERM or SIGINT
+pub async fn on_signal(shutdown: Arc<watch::Sender<bool>>) {
+    let (mut sigterm, mut sigint) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to install signal handlers: {}", e);
+            return;
+        }
+    };
+    let name = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+    info!("Received {}, shutting down", name);
+    let _ = shutdown.send(true);
+}
+
+/// Resolves once shutdown has been requested, or once nobody can request it anymore
+pub async fn requested(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
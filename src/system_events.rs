@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use futures_util::future::{self, FutureExt};
+use tokio::sync::mpsc::UnboundedSender;
+use zbus::fdo;
+use zvariant::{OwnedValue, Value};
+use log::{warn, info, debug, trace};
+
+const LOGIN_SERVICE: &str = "org.freedesktop.login1";
+const LOGIN_PATH: &str = "/org/freedesktop/login1";
+const LOGIN_INTERFACE: &str = "org.freedesktop.login1.Manager";
+
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+const NM_SERVICE: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_INTERFACE: &str = "org.freedesktop.NetworkManager";
+
+/// NetworkManager's `NM_STATE_CONNECTED_GLOBAL`
+const NM_STATE_CONNECTED_GLOBAL: u32 = 70;
+
+/// The `PrimaryConnection` of NetworkManager while there is none
+const NO_CONNECTION: &str = "/";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SystemEvent {
+    /// The system is about to suspend
+    Suspending,
+    Resumed,
+    /// The system lost its internet connectivity
+    NetworkDown,
+    /// The system (re)gained internet connectivity, possibly on another network
+    NetworkUp,
+    /// The system switched to another network without losing connectivity in between
+    NetworkChanged,
+}
+
+/// Sends the event to every receiver, returns false once none of them is listening anymore
+fn broadcast(events: &[UnboundedSender<SystemEvent>], event: SystemEvent) -> bool {
+    events.iter().fold(false, |listening, tx| tx.send(event).is_ok() || listening)
+}
+
+/// The event a `PrepareForSleep` signal announces
+fn sleep_event(signal: &zbus::Message) -> Option<SystemEvent> {
+    match signal.body::<bool>() {
+        Ok(true) => Some(SystemEvent::Suspending),
+        Ok(false) => Some(SystemEvent::Resumed),
+        Err(e) => {
+            warn!("Invalid PrepareForSleep signal: {}", e);
+            None
+        }
+    }
+}
+
+/// The connection NetworkManager now routes through, if the `PropertiesChanged` signal reports a new one
+fn primary_connection(signal: &zbus::Message) -> Option<String> {
+    let (interface, changed, _): (String, HashMap<String, OwnedValue>, Vec<String>) = match signal.body() {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Invalid NetworkManager PropertiesChanged signal: {}", e);
+            return None;
+        }
+    };
+    if interface != NM_INTERFACE {
+        return None;
+    }
+    match changed.get("PrimaryConnection").map(|v| &**v) {
+        Some(Value::ObjectPath(path)) => Some(path.as_str().to_owned()),
+        _ => None,
+    }
+}
+
+/// What NetworkManager's signals told so far
+#[derive(Default)]
+struct Network {
+    connected: Option<bool>,
+    primary: Option<String>,
+}
+
+impl Network {
+    fn state_changed(&mut self, signal: &zbus::Message) -> Option<SystemEvent> {
+        let state = match signal.body::<u32>() {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Invalid NetworkManager StateChanged signal: {}", e);
+                return None;
+            }
+        };
+        // Losing connectivity and regaining it are reported as they happen
+        let connected = state >= NM_STATE_CONNECTED_GLOBAL;
+        if self.connected == Some(connected) {
+            return None;
+        }
+        self.connected = Some(connected);
+        Some(if connected { SystemEvent::NetworkUp } else { SystemEvent::NetworkDown })
+    }
+
+    fn properties_changed(&mut self, signal: &zbus::Message) -> Option<SystemEvent> {
+        // Switching Wi-Fi networks can keep the state at connected, but always changes the primary connection.
+        // Losing the primary connection is left to StateChanged.
+        match primary_connection(signal) {
+            Some(path) if path != NO_CONNECTION && self.primary.as_ref() != Some(&path) => {
+                trace!("Primary connection is now {}", path);
+                self.primary = Some(path);
+                Some(SystemEvent::NetworkChanged)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn signal_rule(sender: &str, path: &str, interface: &str, member: &str) -> String {
+    format!("type='signal',sender='{}',path='{}',interface='{}',member='{}'", sender, path, interface, member)
+}
+
+/// Has the bus send the signals matching `rule`, returns false if it refused
+async fn subscribe(proxy: &fdo::AsyncDBusProxy<'_>, rule: &str) -> bool {
+    match proxy.add_match(rule).await {
+        Ok(()) => true,
+        Err(e) => {
+            info!("Can't subscribe to {}: {}", rule, e);
+            false
+        }
+    }
+}
+
+/// Forwards suspend/resume from logind and connectivity changes from NetworkManager.
+/// Either service being unavailable only disables the events it would have sent.
+/// The signals are read in one place rather than through zbus' signal streams, which starve each other
+/// on a shared connection and block on their match rules when the runtime drops them at shutdown.
+pub async fn run(events: Vec<UnboundedSender<SystemEvent>>) {
+    let conn = match zbus::azync::Connection::new_system().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            warn!("Can't connect to the system bus, suspend and network changes won't be noticed: {}", e);
+            return;
+        }
+    };
+    let proxy = match fdo::AsyncDBusProxy::new(&conn) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            warn!("Can't subscribe to suspend and network changes: {}", e);
+            return;
+        }
+    };
+    if subscribe(&proxy, &signal_rule(LOGIN_SERVICE, LOGIN_PATH, LOGIN_INTERFACE, "PrepareForSleep")).await {
+        debug!("Watching for system suspend");
+    }
+    let state_changes = subscribe(&proxy, &signal_rule(NM_SERVICE, NM_PATH, NM_INTERFACE, "StateChanged")).await;
+    let property_changes = subscribe(&proxy, &signal_rule(NM_SERVICE, NM_PATH, PROPERTIES_INTERFACE, "PropertiesChanged")).await;
+    if state_changes && property_changes {
+        debug!("Watching for network changes");
+    }
+
+    let mut network = Network::default();
+    loop {
+        let signal = match conn.receive_specific(|_| future::ready(Ok(true)).boxed()).await {
+            Ok(signal) => signal,
+            Err(zbus::Error::Io(e)) => {
+                info!("Stopped watching for suspend and network changes: {}", e);
+                return;
+            }
+            Err(e) => {
+                warn!("D-Bus error on the system bus: {}", e);
+                continue;
+            }
+        };
+        let (message_type, interface, member) = match signal.header() {
+            Ok(h) => (h.message_type().ok(), h.interface().ok().flatten().map(str::to_owned), h.member().ok().flatten().map(str::to_owned)),
+            Err(_) => continue,
+        };
+        if message_type != Some(zbus::MessageType::Signal) {
+            continue;
+        }
+        let event = match (interface.as_deref(), member.as_deref()) {
+            (Some(LOGIN_INTERFACE), Some("PrepareForSleep")) => sleep_event(&signal),
+            (Some(NM_INTERFACE), Some("StateChanged")) => network.state_changed(&signal),
+            (Some(PROPERTIES_INTERFACE), Some("PropertiesChanged")) => network.properties_changed(&signal),
+            _ => None,
+        };
+        if let Some(event) = event {
+            if !broadcast(&events, event) {
+                return;
+            }
+        }
+    }
+}
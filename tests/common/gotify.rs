@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use futures_util::sink::SinkExt;
+use futures_util::stream::StreamExt;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+use warp::{Filter, Reply};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::ws::{WebSocket, Ws};
+
+/// Token of the client the daemon logs in with
+pub const CLIENT_TOKEN: &str = "test-client-token";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Application {
+    pub id: i32,
+    pub token: String,
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Message {
+    pub id: i32,
+    pub appid: i32,
+    pub message: String,
+    pub title: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Client {
+    pub id: i32,
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: i32,
+    applications: Vec<Application>,
+    /// Oldest first
+    messages: Vec<Message>,
+    clients: Vec<Client>,
+    /// Whether new messages are sent to connected streams
+    paused: bool,
+    /// Messages posted right after the next page of messages is served, by application
+    after_next_page: Vec<(i32, String)>,
+    /// Whether connections to the stream are answered with an error
+    refuse_streams: bool,
+    /// When each connection to the stream was attempted
+    stream_attempts: Vec<Instant>,
+}
+
+impl State {
+    fn next_id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+#[derive(Clone, Debug)]
+enum StreamEvent {
+    Message(String),
+    Disconnect,
+}
+
+#[derive(Deserialize)]
+struct ApplicationParams {
+    name: String,
+    #[serde(default)]
+    description: String,
+}
+
+#[derive(Deserialize)]
+struct MessageParams {
+    message: String,
+    #[serde(default)]
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct ClientParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    limit: Option<usize>,
+    since: Option<i32>,
+}
+
+/// An in-memory Gotify server with the parts of the REST API and the message stream the daemon uses
+#[derive(Clone)]
+pub struct MockGotify {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+    stream: broadcast::Sender<StreamEvent>,
+    stream_connections: Arc<AtomicUsize>,
+}
+
+fn error(status: StatusCode, description: &str) -> Response {
+    let body = serde_json::json!({
+        "error": status.canonical_reason().unwrap_or(""),
+        "errorCode": status.as_u16(),
+        "errorDescription": description,
+    });
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+fn ok() -> Response {
+    warp::reply::json(&serde_json::json!({})).into_response()
+}
+
+impl MockGotify {
+    pub async fn start() -> Self {
+        let (stream, _) = broadcast::channel(16);
+        let mut mock = MockGotify {
+            url: String::new(),
+            state: Arc::new(Mutex::new(State::default())),
+            stream,
+            stream_connections: Arc::new(AtomicUsize::new(0)),
+        };
+        mock.create_client("UnifiedPush-dbus-Gotify", CLIENT_TOKEN);
+        let (address, server) = warp::serve(mock.routes()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        mock.url = format!("http://{}", address);
+        mock
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn applications(&self) -> Vec<Application> {
+        self.state().applications.clone()
+    }
+
+    pub fn messages(&self) -> Vec<Message> {
+        self.state().messages.clone()
+    }
+
+    pub fn clients(&self) -> Vec<Client> {
+        self.state().clients.clone()
+    }
+
+    fn create_client(&self, name: &str, token: &str) -> Client {
+        let mut state = self.state();
+        let client = Client { id: state.next_id(), name: name.to_owned(), token: token.to_owned() };
+        state.clients.push(client.clone());
+        client
+    }
+
+    pub fn stream_connections(&self) -> usize {
+        self.stream_connections.load(Ordering::SeqCst)
+    }
+
+    /// Stores a message of the application, and sends it to the connected streams unless paused
+    pub fn post_message(&self, appid: i32, text: &str) -> Message {
+        self.store_message(appid, "", text)
+    }
+
+    fn store_message(&self, appid: i32, title: &str, text: &str) -> Message {
+        let mut state = self.state();
+        let message = Message { id: state.next_id(), appid, message: text.to_owned(), title: title.to_owned() };
+        state.messages.push(message.clone());
+        if !state.paused {
+            let _ = self.stream.send(StreamEvent::Message(serde_json::to_string(&message).unwrap()));
+        }
+        message
+    }
+
+    /// Posts the messages once the next page of messages has been served, like messages arriving during catch-up would
+    pub fn post_after_next_page(&self, appid: i32, texts: &[&str]) {
+        self.state().after_next_page.extend(texts.iter().map(|text| (appid, text.to_string())));
+    }
+
+    /// Deletes an application like a user would in the Gotify web UI
+    pub fn delete_application(&self, id: i32) {
+        let mut state = self.state();
+        state.applications.retain(|a| a.id != id);
+        state.messages.retain(|m| m.appid != id);
+    }
+
+    /// While paused, new messages are only available through the REST API
+    pub fn set_paused(&self, paused: bool) {
+        self.state().paused = paused;
+    }
+
+    /// Answers connections to the stream with an error while `refuse` is set, like a server that's down
+    pub fn set_refusing_streams(&self, refuse: bool) {
+        self.state().refuse_streams = refuse;
+    }
+
+    /// When each connection to the stream was attempted, refused ones included
+    pub fn stream_attempts(&self) -> Vec<Instant> {
+        self.state().stream_attempts.clone()
+    }
+
+    /// Drops every stream connection without a close frame, like a network failure would
+    pub fn disconnect_streams(&self) {
+        let _ = self.stream.send(StreamEvent::Disconnect);
+    }
+
+    fn routes(&self) -> warp::filters::BoxedFilter<(Response,)> {
+        let mock = self.clone();
+        let with_mock = warp::any().map(move || mock.clone());
+
+        let list_applications = warp::path!("application").and(warp::get()).and(with_mock.clone())
+            .map(|mock: MockGotify| warp::reply::json(&mock.applications()).into_response());
+        let create_application = warp::path!("application").and(warp::post()).and(warp::body::json()).and(with_mock.clone())
+            .map(|params: ApplicationParams, mock: MockGotify| {
+                let mut state = mock.state();
+                let id = state.next_id();
+                let application = Application { id, token: format!("app-token-{}", id), name: params.name, description: params.description };
+                state.applications.push(application.clone());
+                warp::reply::json(&application).into_response()
+            });
+        let update_application = warp::path!("application" / i32).and(warp::put()).and(warp::body::json()).and(with_mock.clone())
+            .map(|id: i32, params: ApplicationParams, mock: MockGotify| {
+                let mut state = mock.state();
+                match state.applications.iter_mut().find(|a| a.id == id) {
+                    Some(application) => {
+                        application.name = params.name;
+                        application.description = params.description;
+                        warp::reply::json(&*application).into_response()
+                    }
+                    None => error(StatusCode::NOT_FOUND, "app does not exist"),
+                }
+            });
+        let delete_application = warp::path!("application" / i32).and(warp::delete()).and(with_mock.clone())
+            .map(|id: i32, mock: MockGotify| {
+                if mock.applications().iter().any(|a| a.id == id) {
+                    mock.delete_application(id);
+                    ok()
+                } else {
+                    error(StatusCode::NOT_FOUND, "app does not exist")
+                }
+            });
+        let application_messages = warp::path!("application" / i32 / "message").and(warp::get()).and(warp::query()).and(with_mock.clone())
+            .map(|id: i32, query: PageQuery, mock: MockGotify| mock.page(&format!("/application/{}/message", id), Some(id), query));
+        let list_messages = warp::path!("message").and(warp::get()).and(warp::query()).and(with_mock.clone())
+            .map(|query: PageQuery, mock: MockGotify| mock.page("/message", None, query));
+        let create_message = warp::path!("message").and(warp::post())
+            .and(warp::header::optional::<String>("x-gotify-key"))
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::body::json())
+            .and(with_mock.clone())
+            .map(|key: Option<String>, query: HashMap<String, String>, params: MessageParams, mock: MockGotify| {
+                let token = key.or_else(|| query.get("token").cloned()).unwrap_or_default();
+                let appid = match mock.applications().iter().find(|a| a.token == token) {
+                    Some(application) => application.id,
+                    None => return error(StatusCode::UNAUTHORIZED, "you need to provide a valid access token"),
+                };
+                let message = mock.store_message(appid, &params.title, &params.message);
+                warp::reply::json(&message).into_response()
+            });
+        let delete_message = warp::path!("message" / i32).and(warp::delete()).and(with_mock.clone())
+            .map(|id: i32, mock: MockGotify| {
+                let mut state = mock.state();
+                let before = state.messages.len();
+                state.messages.retain(|m| m.id != id);
+                if state.messages.len() < before { ok() } else { error(StatusCode::NOT_FOUND, "message does not exist") }
+            });
+        let list_clients = warp::path!("client").and(warp::get()).and(with_mock.clone())
+            .map(|mock: MockGotify| warp::reply::json(&mock.state().clients).into_response());
+        let create_client = warp::path!("client").and(warp::post()).and(warp::body::json()).and(with_mock.clone())
+            .map(|params: ClientParams, mock: MockGotify| {
+                let token = format!("client-token-{}", mock.state().next_id + 1);
+                warp::reply::json(&mock.create_client(&params.name, &token)).into_response()
+            });
+        let delete_client = warp::path!("client" / i32).and(warp::delete()).and(with_mock.clone())
+            .map(|id: i32, mock: MockGotify| {
+                mock.state().clients.retain(|c| c.id != id);
+                ok()
+            });
+        let current_user = warp::path!("current" / "user").and(warp::get())
+            .and(warp::header::optional::<String>("x-gotify-key"))
+            .and(with_mock.clone())
+            .map(|key: Option<String>, mock: MockGotify| {
+                // Requests without a token use basic auth, which the mock accepts from anyone
+                if key.map_or(false, |key| !mock.clients().iter().any(|c| c.token == key)) {
+                    return error(StatusCode::UNAUTHORIZED, "you need to provide a valid access token");
+                }
+                warp::reply::json(&serde_json::json!({ "id": 1, "name": "admin", "admin": true })).into_response()
+            });
+        let health = warp::path!("health").and(warp::get())
+            .map(|| warp::reply::json(&serde_json::json!({ "health": "green", "database": "green" })).into_response());
+        let stream = warp::path!("stream").and(warp::ws()).and(warp::query::<HashMap<String, String>>()).and(with_mock)
+            .map(|ws: Ws, query: HashMap<String, String>, mock: MockGotify| {
+                if query.get("token").map(|t| t.as_str()) != Some(CLIENT_TOKEN) {
+                    return error(StatusCode::UNAUTHORIZED, "you need to provide a valid access token");
+                }
+                let refused = {
+                    let mut state = mock.state();
+                    state.stream_attempts.push(Instant::now());
+                    state.refuse_streams
+                };
+                if refused {
+                    return error(StatusCode::SERVICE_UNAVAILABLE, "the server is down");
+                }
+                ws.on_upgrade(move |socket| mock.serve_stream(socket)).into_response()
+            });
+
+        list_applications.or(create_application).unify()
+            .or(update_application).unify()
+            .or(delete_application).unify()
+            .or(application_messages).unify()
+            .or(list_messages).unify()
+            .or(create_message).unify()
+            .or(delete_message).unify()
+            .or(list_clients).unify()
+            .or(create_client).unify()
+            .or(delete_client).unify()
+            .or(current_user).unify()
+            .or(health).unify()
+            .or(stream).unify()
+            .boxed()
+    }
+
+    /// Messages with ids below `since`, newest first, like Gotify pages them
+    fn page(&self, path: &str, appid: Option<i32>, query: PageQuery) -> Response {
+        let response = self.build_page(path, appid, query);
+        let posted = std::mem::take(&mut self.state().after_next_page);
+        for (appid, text) in posted {
+            self.post_message(appid, &text);
+        }
+        response
+    }
+
+    /// `next` points at the page after this one, at the same `path`
+    fn build_page(&self, path: &str, appid: Option<i32>, query: PageQuery) -> Response {
+        let limit = query.limit.unwrap_or(100);
+        let since = query.since.filter(|since| *since > 0).unwrap_or(i32::MAX);
+        let state = self.state();
+        let matching: Vec<&Message> = state.messages.iter().rev()
+            .filter(|m| appid.map_or(true, |appid| m.appid == appid) && m.id < since)
+            .collect();
+        let page: Vec<&Message> = matching.iter().take(limit).cloned().collect();
+        let oldest = page.last().map_or(0, |m| m.id);
+        let next = if matching.len() > limit {
+            Some(format!("{}?limit={}&since={}", path, limit, oldest))
+        } else {
+            None
+        };
+        warp::reply::json(&serde_json::json!({
+            "paging": { "next": next, "since": oldest, "size": page.len(), "limit": limit },
+            "messages": page,
+        })).into_response()
+    }
+
+    async fn serve_stream(self, socket: WebSocket) {
+        let (mut tx, mut rx) = socket.split();
+        let mut events = self.stream.subscribe();
+        self.stream_connections.fetch_add(1, Ordering::SeqCst);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(StreamEvent::Message(text)) => {
+                        if tx.send(warp::ws::Message::text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(StreamEvent::Disconnect) | Err(_) => break,
+                },
+                incoming = rx.next() => match incoming {
+                    Some(Ok(message)) if message.is_close() => {
+                        let _ = tx.send(warp::ws::Message::close()).await;
+                        break;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => break,
+                },
+            }
+        }
+        self.stream_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,23 @@
+use zbus::fdo;
+
+use super::PrivateBus;
+
+/// Stands in for `org.freedesktop.login1`, which the daemon looks for on the private bus as its system bus
+pub struct StubLogind {
+    conn: zbus::azync::Connection,
+}
+
+impl StubLogind {
+    pub async fn start(bus: &PrivateBus) -> Self {
+        let conn = bus.connect().await;
+        fdo::AsyncDBusProxy::new(&conn).unwrap()
+            .request_name("org.freedesktop.login1", fdo::RequestNameFlags::DoNotQueue.into()).await.unwrap();
+        StubLogind { conn }
+    }
+
+    /// Announces that the system is about to suspend, or has resumed
+    pub async fn prepare_for_sleep(&self, suspending: bool) {
+        self.conn.emit_signal(None, "/org/freedesktop/login1", "org.freedesktop.login1.Manager", "PrepareForSleep", &suspending)
+            .await.unwrap();
+    }
+}
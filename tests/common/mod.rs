@@ -0,0 +1,401 @@
+//! Runs the daemon against a mock Gotify server on a private session bus, with fake connector apps
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::{self, FutureExt};
+use tempfile::TempDir;
+use tokio::sync::mpsc;
+use zbus::fdo;
+use zvariant::{OwnedValue, Value};
+
+mod gotify;
+mod logind;
+mod secrets;
+
+pub use gotify::{MockGotify, CLIENT_TOKEN};
+pub use logind::StubLogind;
+pub use secrets::StubSecretService;
+
+pub const DISTRIBUTOR_NAME: &str = "org.unifiedpush.Distributor.gotify";
+
+/// How long anything the tests wait for may take
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Polls the condition until it holds, failing the test after `TIMEOUT`
+pub async fn eventually<F: FnMut() -> bool>(what: &str, mut condition: F) {
+    let waited = tokio::time::timeout(TIMEOUT, async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await;
+    if waited.is_err() {
+        panic!("Timed out waiting until {}", what);
+    }
+}
+
+pub fn dict_str(dict: &HashMap<String, OwnedValue>, key: &str) -> String {
+    match dict.get(key).map(|v| &**v) {
+        Some(Value::Str(s)) => s.as_str().to_owned(),
+        other => panic!("Expected a string in {}, got {:?}", key, other),
+    }
+}
+
+fn dict_bytes(dict: &HashMap<String, OwnedValue>, key: &str) -> Vec<u8> {
+    match dict.get(key).map(|v| &**v) {
+        Some(Value::Array(array)) => array.get().iter().map(|v| match v {
+            Value::U8(byte) => *byte,
+            other => panic!("Expected bytes in {}, got {:?}", key, other),
+        }).collect(),
+        other => panic!("Expected bytes in {}, got {:?}", key, other),
+    }
+}
+
+/// A `dbus-daemon --session` of its own, so tests don't touch the user's bus or each other
+pub struct PrivateBus {
+    child: Child,
+    pub address: String,
+    /// Holds the `.service` files the bus can activate
+    _data_dir: TempDir,
+}
+
+impl PrivateBus {
+    pub fn start() -> Self {
+        Self::start_with_services(&[])
+    }
+
+    /// Starts a bus that can activate the services given as `(name, command line)`
+    pub fn start_with_services(services: &[(&str, &str)]) -> Self {
+        let data_dir = TempDir::new().unwrap();
+        let services_dir = data_dir.path().join("dbus-1").join("services");
+        std::fs::create_dir_all(&services_dir).unwrap();
+        for (name, exec) in services {
+            let service = format!("[D-BUS Service]\nName={}\nExec={}\n", name, exec);
+            std::fs::write(services_dir.join(format!("{}.service", name)), service).unwrap();
+        }
+        let mut child = Command::new("dbus-daemon")
+            .args(&["--session", "--nofork", "--print-address"])
+            .env("XDG_DATA_HOME", data_dir.path())
+            .env("XDG_DATA_DIRS", data_dir.path())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("dbus-daemon is needed to run the integration tests");
+        let mut address = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        PrivateBus { child, address: address.trim().to_owned(), _data_dir: data_dir }
+    }
+
+    pub async fn connect(&self) -> zbus::azync::Connection {
+        zbus::azync::Connection::new_for_address(&self.address, true).await.unwrap()
+    }
+
+    pub async fn name_has_owner(&self, name: &str) -> bool {
+        fdo::AsyncDBusProxy::new(&self.connect().await).unwrap().name_has_owner(name).await.unwrap()
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The `unifiedpush_gotify` binary, logged in to the mock server, with short intervals
+pub struct Daemon {
+    child: Child,
+    dir: TempDir,
+}
+
+impl Daemon {
+    pub async fn start(bus: &PrivateBus, gotify: &MockGotify) -> Self {
+        let login = serde_json::json!({ "gotify_base_url": gotify.url, "gotify_device_token": CLIENT_TOKEN });
+        Daemon::start_with_login(bus, login).await
+    }
+
+    /// Starts the daemon with the given `login.json`
+    pub async fn start_with_login(bus: &PrivateBus, login: serde_json::Value) -> Self {
+        Daemon::start_with_config(bus, login, "").await
+    }
+
+    /// Starts the daemon with the given `login.json`, and `settings` added to `config.toml` ahead of its tables
+    pub async fn start_with_config(bus: &PrivateBus, login: serde_json::Value, settings: &str) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        // directories-next lowercases the application name on Linux
+        let config_dir = dir.path().join("unifiedpushgotify");
+        std::fs::create_dir_all(&config_dir).unwrap();
+        std::fs::write(config_dir.join("login.json"), login.to_string()).unwrap();
+        let config = format!(r#"
+            log_level = "debug"
+            {}
+
+            [timeouts]
+            removed_apps_interval = 1
+            retry_interval = 1
+            delivery = 5
+            activation = 1
+
+            [backoff]
+            initial = 0.1
+            max = 0.5
+            jitter = 0.0
+        "#, settings);
+        std::fs::write(config_dir.join("config.toml"), config).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_unifiedpush_gotify"))
+            .arg("--bus").arg("session")
+            .env("XDG_CONFIG_HOME", dir.path())
+            .env("DBUS_SESSION_BUS_ADDRESS", &bus.address)
+            // Suspend and network changes are watched on the system bus, which shouldn't be the host's
+            .env("DBUS_SYSTEM_BUS_ADDRESS", &bus.address)
+            .env_remove("UNIFIEDPUSH_GOTIFY_CONFIG")
+            .spawn()
+            .unwrap();
+        let daemon = Daemon { child, dir };
+
+        let conn = bus.connect().await;
+        let proxy = fdo::AsyncDBusProxy::new(&conn).unwrap();
+        let started = tokio::time::timeout(TIMEOUT, async {
+            while !proxy.name_has_owner(DISTRIBUTOR_NAME).await.unwrap_or(false) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await;
+        assert!(started.is_ok(), "The daemon didn't claim its bus name");
+        daemon
+    }
+}
+
+impl Daemon {
+    /// Where the daemon's `login.json`, `config.toml` and database are
+    pub fn config_dir(&self) -> std::path::PathBuf {
+        self.dir.path().join("unifiedpushgotify")
+    }
+
+    /// Stops the daemon with SIGTERM like a service manager would, keeping its configuration directory until dropped
+    pub async fn stop(&mut self) {
+        let status = self.terminate().await;
+        assert!(status.success(), "The daemon exited with {} after SIGTERM", status);
+    }
+
+    /// Sends SIGTERM and waits for the daemon to exit, failing the test if it doesn't in time
+    pub async fn terminate(&mut self) -> ExitStatus {
+        let sent = tokio::process::Command::new("kill")
+            .args(&["-TERM", &self.child.id().to_string()])
+            .status()
+            .await
+            .unwrap();
+        assert!(sent.success(), "Couldn't send SIGTERM to the daemon");
+        let exited = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let Some(status) = self.child.try_wait().unwrap() {
+                    return status;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await;
+        exited.expect("The daemon didn't exit after SIGTERM")
+    }
+
+    /// Runs `gotify_ctl` against the daemon, returning whether it succeeded and what it printed
+    pub async fn gotify_ctl(&self, bus: &PrivateBus, args: &[&str]) -> (bool, String) {
+        let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_gotify_ctl"))
+            .args(args)
+            .env("XDG_CONFIG_HOME", self.dir.path())
+            .env("DBUS_SESSION_BUS_ADDRESS", &bus.address)
+            .env_remove("UNIFIEDPUSH_GOTIFY_CONFIG")
+            .output()
+            .await
+            .unwrap();
+        (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Runs `gotify_login` on the daemon's configuration, returning whether it succeeded
+    pub async fn gotify_login(&self, bus: &PrivateBus, args: &[&str]) -> bool {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_gotify_login"))
+            .args(args)
+            .env("XDG_CONFIG_HOME", self.dir.path())
+            .env("DBUS_SESSION_BUS_ADDRESS", &bus.address)
+            .env_remove("UNIFIEDPUSH_GOTIFY_CONFIG")
+            .status()
+            .await
+            .unwrap()
+            .success()
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        // Killed rather than terminated, as the test's runtime can't serve the daemon while dropping it
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A call from the distributor to a connector app
+#[derive(Debug, PartialEq)]
+pub enum ConnectorCall {
+    NewEndpoint { token: String, endpoint: String },
+    Message { token: String, message: Vec<u8> },
+    Unregistered { token: String },
+}
+
+/// A connector app that owns its appid on the bus and records the calls it gets
+pub struct Connector {
+    pub appid: String,
+    /// Calls the distributor. A zbus connection can't wait for a reply while the serving task waits for calls.
+    caller: zbus::azync::Connection,
+    calls: mpsc::UnboundedReceiver<ConnectorCall>,
+    calls_tx: mpsc::UnboundedSender<ConnectorCall>,
+    /// The error pushes are answered with instead of being accepted
+    message_error: Arc<Mutex<Option<String>>>,
+    /// Owns the appid and serves the calls, until the app quits
+    server: Option<tokio::task::JoinHandle<()>>,
+}
+
+fn parse_call(message: &zbus::Message) -> Option<ConnectorCall> {
+    let header = message.header().ok()?;
+    let interface = header.interface().ok()??;
+    let member = header.member().ok()??;
+    match (interface, member) {
+        ("org.unifiedpush.Connector2", "NewEndpoint") => message.body::<HashMap<String, OwnedValue>>().ok()
+            .map(|args| ConnectorCall::NewEndpoint { token: dict_str(&args, "token"), endpoint: dict_str(&args, "endpoint") }),
+        ("org.unifiedpush.Connector2", "Message") => message.body::<HashMap<String, OwnedValue>>().ok()
+            .map(|args| ConnectorCall::Message { token: dict_str(&args, "token"), message: dict_bytes(&args, "message") }),
+        ("org.unifiedpush.Connector2", "Unregistered") => message.body::<HashMap<String, OwnedValue>>().ok()
+            .map(|args| ConnectorCall::Unregistered { token: dict_str(&args, "token") }),
+        ("org.unifiedpush.Connector1", "NewEndpoint") => message.body::<(String, String)>().ok()
+            .map(|(token, endpoint)| ConnectorCall::NewEndpoint { token, endpoint }),
+        ("org.unifiedpush.Connector1", "Message") => message.body::<(String, String, String)>().ok()
+            .map(|(token, message, _)| ConnectorCall::Message { token, message: message.into_bytes() }),
+        ("org.unifiedpush.Connector1", "Unregistered") => message.body::<String>().ok()
+            .map(|token| ConnectorCall::Unregistered { token }),
+        _ => None,
+    }
+}
+
+/// The next method call on the connection, for connections that only serve
+async fn next_method_call(conn: &zbus::azync::Connection) -> zbus::Result<zbus::Message> {
+    conn.receive_specific(|message| {
+        let is_call = message.header().map_or(false, |h| h.message_type().ok() == Some(zbus::MessageType::MethodCall));
+        future::ready(Ok(is_call)).boxed()
+    }).await
+}
+
+async fn serve_connector(
+    conn: zbus::azync::Connection,
+    calls: mpsc::UnboundedSender<ConnectorCall>,
+    message_error: Arc<Mutex<Option<String>>>) {
+    while let Ok(message) = next_method_call(&conn).await {
+        let v2 = message.header().ok()
+            .and_then(|h| h.interface().ok().flatten().map(|i| i == "org.unifiedpush.Connector2"))
+            .unwrap_or(false);
+        let error = message_error.lock().unwrap().clone();
+        match (parse_call(&message), error) {
+            (Some(ConnectorCall::Message { .. }), Some(error)) => {
+                let _ = conn.reply_error(&message, &error, &"Refused").await;
+            }
+            (Some(call), _) => {
+                let _ = if v2 {
+                    conn.reply(&message, &HashMap::<&str, Value>::new()).await
+                } else {
+                    conn.reply(&message, &()).await
+                };
+                let _ = calls.send(call);
+            }
+            (None, _) => {
+                let _ = conn.reply_error(&message, "org.freedesktop.DBus.Error.UnknownMethod", &"Unknown method").await;
+            }
+        }
+    }
+}
+
+impl Connector {
+    pub async fn start(bus: &PrivateBus, appid: &str) -> Self {
+        let (calls_tx, calls) = mpsc::unbounded_channel();
+        let mut connector = Connector {
+            appid: appid.to_owned(),
+            caller: bus.connect().await,
+            calls,
+            calls_tx,
+            message_error: Arc::new(Mutex::new(None)),
+            server: None,
+        };
+        connector.claim_name(bus).await;
+        connector
+    }
+
+    /// Takes the appid on the bus and serves calls, like the app starting up
+    pub async fn claim_name(&mut self, bus: &PrivateBus) {
+        let conn = bus.connect().await;
+        fdo::AsyncDBusProxy::new(&conn).unwrap()
+            .request_name(&self.appid, fdo::RequestNameFlags::DoNotQueue.into()).await.unwrap();
+        self.server = Some(tokio::spawn(serve_connector(conn, self.calls_tx.clone(), self.message_error.clone())));
+    }
+
+    /// Closes the connection that owns the appid, like the app exiting, and waits until the bus noticed
+    pub async fn quit(&mut self) {
+        if let Some(server) = self.server.take() {
+            server.abort();
+            let _ = server.await;
+        }
+        let proxy = fdo::AsyncDBusProxy::new(&self.caller).unwrap();
+        while proxy.name_has_owner(&self.appid).await.unwrap() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Answers the pushes that arrive from now on with the D-Bus error `name`
+    pub fn refuse_messages_with(&self, name: &str) {
+        *self.message_error.lock().unwrap() = Some(name.to_owned());
+    }
+
+    async fn call_distributor<B>(&self, interface: &str, method: &str, body: &B) -> zbus::Result<zbus::Message>
+    where B: serde::Serialize + zvariant::Type {
+        self.caller.call_method(Some(DISTRIBUTOR_NAME), "/org/unifiedpush/Distributor", Some(interface), method, body).await
+    }
+
+    /// Registers through `org.unifiedpush.Distributor2`, returning the result dictionary
+    pub async fn register(&self, token: &str) -> HashMap<String, OwnedValue> {
+        let mut args = HashMap::new();
+        args.insert("service", Value::from(self.appid.as_str()));
+        args.insert("token", Value::from(token));
+        args.insert("description", Value::from("Integration test"));
+        self.call_distributor("org.unifiedpush.Distributor2", "Register", &args).await.unwrap().body().unwrap()
+    }
+
+    /// Registers like connectors written against the original spec, without a description
+    pub async fn register_v1(&self, token: &str) -> (String, String) {
+        self.call_distributor("org.unifiedpush.Distributor1", "Register", &(self.appid.as_str(), token)).await.unwrap().body().unwrap()
+    }
+
+    /// Registers through `org.unifiedpush.Distributor1` with a description, the spec's three argument form
+    pub async fn register_with_description(&self, token: &str, description: &str) -> (String, String) {
+        self.call_distributor("org.unifiedpush.Distributor1", "Register", &(self.appid.as_str(), token, description)).await.unwrap().body().unwrap()
+    }
+
+    /// The introspection data of the daemon's object at `path`
+    pub async fn introspect(&self, path: &str) -> String {
+        self.caller.call_method(Some(DISTRIBUTOR_NAME), path, Some("org.freedesktop.DBus.Introspectable"), "Introspect", &())
+            .await.unwrap().body().unwrap()
+    }
+
+    pub async fn unregister(&self, token: &str) {
+        let mut args = HashMap::new();
+        args.insert("token", Value::from(token));
+        self.call_distributor("org.unifiedpush.Distributor2", "Unregister", &args).await.unwrap();
+    }
+
+    /// The next call from the distributor, failing the test if none arrives in time
+    pub async fn next_call(&mut self) -> ConnectorCall {
+        match tokio::time::timeout(TIMEOUT, self.calls.recv()).await {
+            Ok(Some(call)) => call,
+            Ok(None) => panic!("{} stopped serving calls", self.appid),
+            Err(_) => panic!("{} got no call from the distributor", self.appid),
+        }
+    }
+}
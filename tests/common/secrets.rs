@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use zbus::fdo;
+use zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+
+use super::{next_method_call, PrivateBus};
+
+const SESSION_PATH: &str = "/org/freedesktop/secrets/session/1";
+const COLLECTION_PATH: &str = "/org/freedesktop/secrets/collection/login";
+
+type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+#[derive(Clone, Debug)]
+pub struct Item {
+    pub path: String,
+    pub attributes: HashMap<String, String>,
+    pub secret: Vec<u8>,
+}
+
+/// An always unlocked `org.freedesktop.secrets` with one collection, keeping its items in memory
+#[derive(Clone)]
+pub struct StubSecretService {
+    items: Arc<Mutex<Vec<Item>>>,
+}
+
+fn path(path: &str) -> ObjectPath<'_> {
+    ObjectPath::try_from(path).unwrap()
+}
+
+fn matches(item: &Item, attributes: &HashMap<String, String>) -> bool {
+    attributes.iter().all(|(key, value)| item.attributes.get(key) == Some(value))
+}
+
+impl StubSecretService {
+    pub async fn start(bus: &PrivateBus) -> Self {
+        let conn = bus.connect().await;
+        fdo::AsyncDBusProxy::new(&conn).unwrap()
+            .request_name("org.freedesktop.secrets", fdo::RequestNameFlags::DoNotQueue.into()).await.unwrap();
+        let service = StubSecretService { items: Arc::new(Mutex::new(Vec::new())) };
+        tokio::spawn(service.clone().serve(conn));
+        service
+    }
+
+    pub fn items(&self) -> Vec<Item> {
+        self.items.lock().unwrap().clone()
+    }
+
+    /// Stores a secret like `CreateItem` with `replace` set would
+    pub fn insert(&self, attributes: HashMap<String, String>, secret: &[u8]) -> String {
+        let mut items = self.items.lock().unwrap();
+        if let Some(item) = items.iter_mut().find(|i| i.attributes == attributes) {
+            item.secret = secret.to_vec();
+            return item.path.clone();
+        }
+        let path = format!("{}/{}", COLLECTION_PATH, items.len() + 1);
+        items.push(Item { path: path.clone(), attributes, secret: secret.to_vec() });
+        path
+    }
+
+    async fn serve(self, conn: zbus::azync::Connection) {
+        while let Ok(message) = next_method_call(&conn).await {
+            let header = match message.header() {
+                Ok(header) => header,
+                Err(_) => continue,
+            };
+            let member = header.member().ok().flatten().unwrap_or_default().to_owned();
+            let _ = match member.as_str() {
+                "OpenSession" => conn.reply(&message, &(Value::from(""), path(SESSION_PATH))).await,
+                "ReadAlias" => conn.reply(&message, &path(COLLECTION_PATH)).await,
+                "Unlock" => {
+                    let paths: Vec<OwnedObjectPath> = message.body().unwrap();
+                    conn.reply(&message, &(paths, path("/"))).await
+                }
+                "SearchItems" => {
+                    let attributes: HashMap<String, String> = message.body().unwrap();
+                    let found: Vec<ObjectPath> = self.items().iter()
+                        .filter(|item| matches(item, &attributes))
+                        .map(|item| ObjectPath::try_from(item.path.clone()).unwrap())
+                        .collect();
+                    conn.reply(&message, &(found, Vec::<ObjectPath>::new())).await
+                }
+                "GetSecrets" => {
+                    let (paths, _): (Vec<OwnedObjectPath>, OwnedObjectPath) = message.body().unwrap();
+                    let items = self.items();
+                    let secrets: HashMap<OwnedObjectPath, Secret> = paths.into_iter()
+                        .filter_map(|p| {
+                            let item = items.iter().find(|i| i.path == p.as_str())?;
+                            let session = OwnedObjectPath::try_from(SESSION_PATH).unwrap();
+                            Some((p, (session, Vec::new(), item.secret.clone(), "text/plain".to_owned())))
+                        })
+                        .collect();
+                    conn.reply(&message, &secrets).await
+                }
+                "CreateItem" => {
+                    let (properties, (_, _, value, _), _): (HashMap<String, OwnedValue>, Secret, bool) = message.body().unwrap();
+                    let attributes = match properties.get("org.freedesktop.Secret.Item.Attributes").map(|v| &**v) {
+                        Some(Value::Dict(dict)) => HashMap::<String, String>::try_from(dict.clone()).unwrap(),
+                        other => panic!("Expected item attributes, got {:?}", other),
+                    };
+                    let item = self.insert(attributes, &value);
+                    conn.reply(&message, &(path(&item), path("/"))).await
+                }
+                "Delete" => {
+                    let item = header.path().ok().flatten().map(|p| p.as_str().to_owned()).unwrap_or_default();
+                    self.items.lock().unwrap().retain(|i| i.path != item);
+                    conn.reply(&message, &path("/")).await
+                }
+                _ => conn.reply_error(&message, "org.freedesktop.DBus.Error.UnknownMethod", &"Unknown method").await,
+            };
+        }
+    }
+}
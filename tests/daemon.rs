@@ -0,0 +1,402 @@
+mod common;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use common::{eventually, dict_str, ConnectorCall, Connector, Daemon, MockGotify, PrivateBus, StubLogind, StubSecretService, CLIENT_TOKEN, DISTRIBUTOR_NAME};
+
+const APPID: &str = "org.example.TestApp";
+
+/// Starts the mock server, a bus and the daemon, and registers a connector app with `token`.
+/// Returns the app's Gotify application id once the connector got its endpoint.
+async fn setup(token: &str) -> (MockGotify, PrivateBus, Daemon, Connector, i32) {
+    let gotify = MockGotify::start().await;
+    let bus = PrivateBus::start();
+    let daemon = Daemon::start(&bus, &gotify).await;
+    let mut connector = Connector::start(&bus, APPID).await;
+    let result = connector.register(token).await;
+    assert_eq!(dict_str(&result, "success"), "REGISTRATION_SUCCEEDED");
+    let applications = gotify.applications();
+    assert_eq!(applications.len(), 1);
+    let application = &applications[0];
+    assert_eq!(application.name, APPID);
+    assert_eq!(connector.next_call().await, ConnectorCall::NewEndpoint {
+        token: token.to_owned(),
+        endpoint: format!("{}/UP?token={}", gotify.url, application.token),
+    });
+    let id = application.id;
+    (gotify, bus, daemon, connector, id)
+}
+
+#[tokio::test]
+async fn registers_and_unregisters() {
+    let (gotify, bus, daemon, mut connector, _) = setup("token-1").await;
+    let application = gotify.applications().remove(0);
+
+    // Registering again returns the same endpoint instead of creating another application
+    let result = connector.register("token-1").await;
+    assert_eq!(dict_str(&result, "success"), "REGISTRATION_SUCCEEDED");
+    assert_eq!(connector.next_call().await, ConnectorCall::NewEndpoint {
+        token: "token-1".to_owned(),
+        endpoint: format!("{}/UP?token={}", gotify.url, application.token),
+    });
+    assert_eq!(gotify.applications().len(), 1);
+
+    // The three argument Register keeps the description it's given
+    assert_eq!(connector.register_with_description("token-1", "Chat notifications").await, ("NEW_ENDPOINT".to_owned(), String::new()));
+    assert_eq!(connector.next_call().await, ConnectorCall::NewEndpoint {
+        token: "token-1".to_owned(),
+        endpoint: format!("{}/UP?token={}", gotify.url, application.token),
+    });
+    let (ok, shown) = daemon.gotify_ctl(&bus, &["show", APPID]).await;
+    assert!(ok);
+    assert!(shown.contains("description: Chat notifications"), "Unexpected registration: {}", shown);
+
+    connector.unregister("token-1").await;
+    assert_eq!(connector.next_call().await, ConnectorCall::Unregistered { token: "token-1".to_owned() });
+    assert!(gotify.applications().is_empty());
+}
+
+#[tokio::test]
+async fn accepts_registrations_without_description() {
+    let gotify = MockGotify::start().await;
+    let bus = PrivateBus::start();
+    let _daemon = Daemon::start(&bus, &gotify).await;
+    let mut connector = Connector::start(&bus, APPID).await;
+    assert_eq!(connector.register_v1("token-1").await, ("NEW_ENDPOINT".to_owned(), String::new()));
+    let application = gotify.applications().remove(0);
+    assert_eq!(connector.next_call().await, ConnectorCall::NewEndpoint {
+        token: "token-1".to_owned(),
+        endpoint: format!("{}/UP?token={}", gotify.url, application.token),
+    });
+}
+
+#[tokio::test]
+async fn registers_once_when_called_twice_at_once() {
+    let gotify = MockGotify::start().await;
+    let bus = PrivateBus::start();
+    let _daemon = Daemon::start(&bus, &gotify).await;
+    let connector = Connector::start(&bus, APPID).await;
+    let (first, second) = futures_util::future::join(connector.register("token-1"), connector.register("token-1")).await;
+    assert_eq!(dict_str(&first, "success"), "REGISTRATION_SUCCEEDED");
+    assert_eq!(dict_str(&second, "success"), "REGISTRATION_SUCCEEDED");
+    assert_eq!(gotify.applications().len(), 1);
+}
+
+#[tokio::test]
+async fn introspects_the_objects_and_the_paths_above_them() {
+    let gotify = MockGotify::start().await;
+    let bus = PrivateBus::start();
+    let _daemon = Daemon::start(&bus, &gotify).await;
+    let connector = Connector::start(&bus, APPID).await;
+
+    assert!(connector.introspect("/").await.contains(r#"<node name="org"/>"#));
+    let parent = connector.introspect("/org/unifiedpush").await;
+    assert!(parent.contains(r#"<node name="Distributor"/>"#));
+    assert!(parent.contains(r#"<node name="Gotify"/>"#));
+
+    let distributor = connector.introspect("/org/unifiedpush/Distributor").await;
+    assert!(distributor.contains(r#"<interface name="org.unifiedpush.Distributor1">"#));
+    assert!(distributor.contains(r#"<interface name="org.unifiedpush.Distributor2">"#));
+    // The overload without a description is accepted, but not listed
+    assert_eq!(distributor.matches(r#"<method name="Register">"#).count(), 2);
+
+    let manager = connector.introspect("/org/unifiedpush/Gotify/Manager").await;
+    assert!(manager.contains(r#"<signal name="ConnectionStateChanged">"#));
+}
+
+#[tokio::test]
+async fn refuses_a_token_of_another_app() {
+    let (_gotify, bus, _daemon, _connector, _) = setup("token-1").await;
+    let other = Connector::start(&bus, "org.example.OtherApp").await;
+    let result = other.register("token-1").await;
+    assert_eq!(dict_str(&result, "success"), "REGISTRATION_FAILED");
+}
+
+#[tokio::test]
+async fn delivers_pushes_and_deletes_them() {
+    let (gotify, _bus, _daemon, mut connector, id) = setup("token-1").await;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    gotify.post_message(id, "hello");
+    assert_eq!(connector.next_call().await, ConnectorCall::Message { token: "token-1".to_owned(), message: b"hello".to_vec() });
+    eventually("the delivered message is deleted", || gotify.messages().is_empty()).await;
+}
+
+#[tokio::test]
+async fn catches_up_after_reconnecting() {
+    let (gotify, _bus, _daemon, mut connector, id) = setup("token-1").await;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    // Both messages arrive while the stream is down, and are delivered in order
+    gotify.set_paused(true);
+    gotify.post_message(id, "first");
+    gotify.post_message(id, "second");
+    gotify.disconnect_streams();
+    gotify.set_paused(false);
+
+    assert_eq!(connector.next_call().await, ConnectorCall::Message { token: "token-1".to_owned(), message: b"first".to_vec() });
+    assert_eq!(connector.next_call().await, ConnectorCall::Message { token: "token-1".to_owned(), message: b"second".to_vec() });
+    eventually("the delivered messages are deleted", || gotify.messages().is_empty()).await;
+}
+
+#[tokio::test]
+async fn backs_off_while_the_server_refuses_the_stream() {
+    let gotify = MockGotify::start().await;
+    gotify.set_refusing_streams(true);
+    let bus = PrivateBus::start();
+    let _daemon = Daemon::start(&bus, &gotify).await;
+
+    // The test configuration backs off 0.1, 0.2, 0.4 and then at most 0.5 seconds
+    eventually("the daemon tried to connect five times", || gotify.stream_attempts().len() >= 5).await;
+    let attempts = gotify.stream_attempts();
+    let gaps: Vec<f64> = attempts.windows(2).map(|w| (w[1] - w[0]).as_secs_f64()).collect();
+    for (gap, delay) in gaps.iter().zip(&[0.1, 0.2, 0.4, 0.5]) {
+        assert!(*gap >= delay * 0.9 && *gap < delay + 0.4, "Waited {} instead of {} seconds in {:?}", gap, delay, gaps);
+    }
+    assert_eq!(gotify.stream_connections(), 0);
+
+    gotify.set_refusing_streams(false);
+    eventually("the daemon connects once the server is back", || gotify.stream_connections() > 0).await;
+}
+
+#[tokio::test]
+async fn reconnects_and_catches_up_after_resuming() {
+    let gotify = MockGotify::start().await;
+    let bus = PrivateBus::start();
+    let logind = StubLogind::start(&bus).await;
+    let _daemon = Daemon::start(&bus, &gotify).await;
+    let mut connector = Connector::start(&bus, APPID).await;
+    assert_eq!(dict_str(&connector.register("token-1").await, "success"), "REGISTRATION_SUCCEEDED");
+    assert!(matches!(connector.next_call().await, ConnectorCall::NewEndpoint { .. }));
+    let id = gotify.applications()[0].id;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    // Until the daemon subscribed to PrepareForSleep, the announcement goes unnoticed
+    let suspended = tokio::time::timeout(Duration::from_secs(10), async {
+        while gotify.stream_connections() > 0 {
+            logind.prepare_for_sleep(true).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }).await;
+    assert!(suspended.is_ok(), "The daemon kept its connection when the system suspended");
+
+    // The daemon waits for the system to resume instead of backing off
+    gotify.post_message(id, "while asleep");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(gotify.stream_connections(), 0);
+
+    logind.prepare_for_sleep(false).await;
+    eventually("the daemon reconnects", || gotify.stream_connections() > 0).await;
+    assert_eq!(connector.next_call().await, ConnectorCall::Message { token: "token-1".to_owned(), message: b"while asleep".to_vec() });
+}
+
+#[tokio::test]
+async fn catches_up_across_pages_while_messages_arrive() {
+    let (gotify, _bus, _daemon, mut connector, id) = setup("token-1").await;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    // More than two pages of 100 arrive while the stream is down, and more during catch-up
+    gotify.set_paused(true);
+    let missed: Vec<String> = (1..=250).map(|i| format!("missed-{}", i)).collect();
+    for text in &missed {
+        gotify.post_message(id, text);
+    }
+    gotify.post_after_next_page(id, &["late-1", "late-2", "late-3"]);
+    gotify.disconnect_streams();
+    gotify.set_paused(false);
+
+    let expected = missed.iter().map(String::as_str).chain(vec!["late-1", "late-2", "late-3"]);
+    for text in expected {
+        assert_eq!(connector.next_call().await, ConnectorCall::Message { token: "token-1".to_owned(), message: text.as_bytes().to_vec() });
+    }
+    eventually("the delivered messages are deleted", || gotify.messages().is_empty()).await;
+}
+
+#[tokio::test]
+async fn ignores_messages_of_other_applications() {
+    let (gotify, _bus, _daemon, mut connector, id) = setup("token-1").await;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    gotify.post_message(id + 1000, "not a push");
+    gotify.post_message(id, "push");
+    assert_eq!(connector.next_call().await, ConnectorCall::Message { token: "token-1".to_owned(), message: b"push".to_vec() });
+    eventually("only the other message is left", || gotify.messages().len() == 1).await;
+}
+
+#[tokio::test]
+async fn unregisters_apps_whose_application_was_deleted() {
+    let (gotify, _bus, _daemon, mut connector, id) = setup("token-1").await;
+    gotify.delete_application(id);
+    assert_eq!(connector.next_call().await, ConnectorCall::Unregistered { token: "token-1".to_owned() });
+}
+
+#[tokio::test]
+async fn activates_sleeping_apps_to_deliver_pushes() {
+    let gotify = MockGotify::start().await;
+    // The service stands in for the app's executable, the test takes the name once the bus ran it
+    let activated = tempfile::TempDir::new().unwrap();
+    let marker = activated.path().join("activated");
+    let exec = format!("/bin/sh -c \"touch {} && exec sleep 10\"", marker.display());
+    let bus = PrivateBus::start_with_services(&[(APPID, &exec)]);
+    let _daemon = Daemon::start(&bus, &gotify).await;
+    let mut connector = Connector::start(&bus, APPID).await;
+    assert_eq!(dict_str(&connector.register("token-1").await, "success"), "REGISTRATION_SUCCEEDED");
+    assert!(matches!(connector.next_call().await, ConnectorCall::NewEndpoint { .. }));
+    let id = gotify.applications()[0].id;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    connector.quit().await;
+    gotify.post_message(id, "wake up");
+    eventually("the bus activates the app", || marker.exists()).await;
+    connector.claim_name(&bus).await;
+
+    assert_eq!(connector.next_call().await, ConnectorCall::Message { token: "token-1".to_owned(), message: b"wake up".to_vec() });
+    eventually("the delivered message is deleted", || gotify.messages().is_empty()).await;
+}
+
+#[tokio::test]
+async fn unregisters_apps_that_no_longer_take_pushes() {
+    let (gotify, _bus, _daemon, mut connector, id) = setup("token-1").await;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    connector.refuse_messages_with("org.freedesktop.DBus.Error.UnknownMethod");
+    gotify.post_message(id, "hello");
+    assert_eq!(connector.next_call().await, ConnectorCall::Unregistered { token: "token-1".to_owned() });
+    eventually("the application is deleted", || gotify.applications().is_empty()).await;
+}
+
+#[tokio::test]
+async fn drops_pushes_the_app_rejects() {
+    let (gotify, _bus, _daemon, connector, id) = setup("token-1").await;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    connector.refuse_messages_with("org.example.TestApp.Error.Full");
+    gotify.post_message(id, "rejected");
+    eventually("the rejected message is deleted", || gotify.messages().is_empty()).await;
+    assert_eq!(gotify.applications().len(), 1);
+}
+
+#[tokio::test]
+async fn shuts_down_cleanly_on_sigterm() {
+    let (gotify, bus, mut daemon, _connector, _) = setup("token-1").await;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+
+    let status = daemon.terminate().await;
+    assert!(status.success(), "The daemon exited with {}", status);
+    assert!(!bus.name_has_owner(DISTRIBUTOR_NAME).await);
+    eventually("the stream is closed", || gotify.stream_connections() == 0).await;
+}
+
+#[tokio::test]
+async fn reads_the_device_token_from_the_secret_service() {
+    let gotify = MockGotify::start().await;
+    let bus = PrivateBus::start();
+    let secrets = StubSecretService::start(&bus).await;
+    let attributes: HashMap<String, String> = vec![
+        ("application", "unifiedpush_gotify"),
+        ("account", "default"),
+        ("gotify_base_url", gotify.url.as_str()),
+    ].into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect();
+    secrets.insert(attributes, CLIENT_TOKEN.as_bytes());
+
+    // No token in login.json, so the stream only connects with the one from the keyring
+    let _daemon = Daemon::start_with_login(&bus, serde_json::json!({ "gotify_base_url": gotify.url })).await;
+    eventually("the daemon is connected to the stream", || gotify.stream_connections() > 0).await;
+}
+
+#[tokio::test]
+async fn logout_deletes_the_client_and_applications() {
+    let (gotify, bus, mut daemon, _connector, _) = setup("token-1").await;
+    // Logging out refuses while the daemon is running
+    assert!(!daemon.gotify_login(&bus, &["logout", "--delete-applications"]).await);
+    daemon.stop().await;
+
+    assert!(daemon.gotify_login(&bus, &["logout", "--delete-applications"]).await);
+    assert!(gotify.clients().is_empty());
+    assert!(gotify.applications().is_empty());
+    assert!(!daemon.config_dir().join("login.json").exists());
+    assert!(!daemon.config_dir().join("database.db").exists());
+}
+
+#[tokio::test]
+async fn logs_in_with_an_existing_client_token() {
+    let (gotify, bus, mut daemon, _connector, _) = setup("token-1").await;
+    daemon.stop().await;
+
+    assert!(!daemon.gotify_login(&bus, &["--account", "second", "--url", &gotify.url, "--token", "not-a-client-token"]).await);
+    assert!(daemon.gotify_login(&bus, &["--account", "second", "--url", &gotify.url, "--token", CLIENT_TOKEN]).await);
+    // No Secret Service runs on the bus, so the token is kept in login.json
+    let logins: serde_json::Value = serde_json::from_slice(&std::fs::read(daemon.config_dir().join("login.json")).unwrap()).unwrap();
+    let second = logins["accounts"].as_array().unwrap().iter().find(|a| a["name"] == "second").unwrap();
+    assert_eq!(second["gotify_device_token"], CLIENT_TOKEN);
+    assert_eq!(gotify.clients().len(), 1);
+
+    // The client wasn't created by gotify_login, so logging out leaves it on the server
+    assert!(daemon.gotify_login(&bus, &["logout", "--account", "second"]).await);
+    assert_eq!(gotify.clients().len(), 1);
+}
+
+#[tokio::test]
+async fn routes_apps_to_their_accounts() {
+    let personal = MockGotify::start().await;
+    let work = MockGotify::start().await;
+    let bus = PrivateBus::start();
+    let login = serde_json::json!({ "accounts": [
+        { "name": "personal", "gotify_base_url": personal.url, "gotify_device_token": CLIENT_TOKEN },
+        { "name": "work", "gotify_base_url": work.url, "gotify_device_token": CLIENT_TOKEN },
+    ]});
+    let _daemon = Daemon::start_with_config(&bus, login, r#"
+        [[routes]]
+        appid = "org.example.Work*"
+        account = "work"
+    "#).await;
+
+    let mut chat = Connector::start(&bus, "org.example.Chat").await;
+    let mut work_chat = Connector::start(&bus, "org.example.WorkChat").await;
+    assert_eq!(dict_str(&chat.register("token-1").await, "success"), "REGISTRATION_SUCCEEDED");
+    assert_eq!(dict_str(&work_chat.register("token-2").await, "success"), "REGISTRATION_SUCCEEDED");
+
+    // Unrouted apps go to the first account
+    let personal_apps = personal.applications();
+    let work_apps = work.applications();
+    assert_eq!(personal_apps.len(), 1);
+    assert_eq!(work_apps.len(), 1);
+    assert_eq!(personal_apps[0].name, "org.example.Chat");
+    assert_eq!(work_apps[0].name, "org.example.WorkChat");
+    // Both servers hand out the same application id, which must not mix up the registrations
+    assert_eq!(personal_apps[0].id, work_apps[0].id);
+    assert_eq!(chat.next_call().await, ConnectorCall::NewEndpoint {
+        token: "token-1".to_owned(),
+        endpoint: format!("{}/UP?token={}", personal.url, personal_apps[0].token),
+    });
+    assert_eq!(work_chat.next_call().await, ConnectorCall::NewEndpoint {
+        token: "token-2".to_owned(),
+        endpoint: format!("{}/UP?token={}", work.url, work_apps[0].token),
+    });
+
+    eventually("both accounts are connected", || personal.stream_connections() > 0 && work.stream_connections() > 0).await;
+    work.post_message(work_apps[0].id, "work push");
+    personal.post_message(personal_apps[0].id, "personal push");
+    assert_eq!(work_chat.next_call().await, ConnectorCall::Message { token: "token-2".to_owned(), message: b"work push".to_vec() });
+    assert_eq!(chat.next_call().await, ConnectorCall::Message { token: "token-1".to_owned(), message: b"personal push".to_vec() });
+    eventually("the delivered messages are deleted", || personal.messages().is_empty() && work.messages().is_empty()).await;
+}
+
+#[tokio::test]
+async fn manages_registrations_with_gotify_ctl() {
+    let (gotify, bus, daemon, mut connector, _) = setup("token-1").await;
+
+    let (ok, listed) = daemon.gotify_ctl(&bus, &["list"]).await;
+    assert!(ok);
+    assert!(listed.contains(APPID) && listed.contains("token-1"), "Unexpected listing: {}", listed);
+
+    let (ok, connections) = daemon.gotify_ctl(&bus, &["connections"]).await;
+    assert!(ok);
+    assert!(connections.starts_with("default\t"), "Unexpected connection states: {}", connections);
+
+    assert!(daemon.gotify_ctl(&bus, &["unregister", "token-1"]).await.0);
+    assert_eq!(connector.next_call().await, ConnectorCall::Unregistered { token: "token-1".to_owned() });
+    assert!(gotify.applications().is_empty());
+    assert!(!daemon.gotify_ctl(&bus, &["show", APPID]).await.0);
+}